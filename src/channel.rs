@@ -0,0 +1,25 @@
+// Delivery guarantees of a channel. Only the sender needs to know them, every packet tells the
+// receiver whether it's ACKed and whether it's sequenced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChannelMode {
+  // ACKed and retransmitted, read in send order
+  ReliableOrdered,
+  // ACKed and retransmitted, read as soon as reassembled
+  #[default]
+  ReliableUnordered,
+  // Sent once, anything older than the newest datagram already read is discarded
+  UnreliableSequenced,
+  // Sent once, read as soon as reassembled
+  Unreliable,
+}
+
+impl ChannelMode {
+  pub fn is_reliable(&self) -> bool {
+    matches!(self, ChannelMode::ReliableOrdered | ChannelMode::ReliableUnordered)
+  }
+
+  // Whether datagrams of this channel carry sequence numbers
+  pub fn is_sequenced(&self) -> bool {
+    matches!(self, ChannelMode::ReliableOrdered | ChannelMode::UnreliableSequenced)
+  }
+}
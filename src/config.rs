@@ -0,0 +1,394 @@
+use std::{collections::HashMap, io, net::SocketAddr, time::Duration};
+
+use crate::{
+  channel::ChannelMode, 
+  congestion::CongestionControl, 
+  crypto::{PreSharedKey, ENCRYPTION_OVERHEAD}, 
+  filter::RateLimit, 
+  noise::{NoiseKeypair, MAX_HANDSHAKE_MESSAGE}, 
+  types::{HEADER_SIZE, MIN_SEGMENT_SIZE}, 
+  AckUdp
+};
+
+#[derive(Debug, Clone)]
+pub struct AckUdpConfig {
+  pub segment_size: usize, // Max payload bytes carried by one packet
+  pub recv_buffer_size: usize, // Must fit the header plus a full segment, and the encryption overhead with a `psk`
+  pub resend_interval: Duration, // Initial retransmission timeout, used until the peer's RTT is measured
+  pub min_rto: Duration, // Lower bound of the adaptive retransmission timeout
+  pub max_rto: Duration, // Upper bound of the adaptive retransmission timeout, backoff included
+  pub timer_granularity: Duration, // How often pending OUTcome datagrams are checked for expired timeouts
+  pub max_resend_attempts: u16, // Resend rounds before the datagram is dropped
+  pub incoming_expiry: Duration, // How long an incomplete INcome datagram is kept without new segments
+  pub incoming_queue_capacity: usize, // Received packets waiting to be processed, further ones are discarded until there's room
+  pub dedup_window_size: usize, // Delivered datagram ids remembered per peer to drop retransmitted duplicates
+  pub dedup_window_ttl: Duration, // How long a delivered datagram id is remembered, at least as long as a sender keeps resending (`max_rto * max_resend_attempts`)
+  pub congestion_control: CongestionControl, // Limits OUTcome bytes in flight per peer
+  pub receive_window: usize, // INcome bytes buffered (reassembling or waiting for `recv`) before new segments are refused, on every channel alike. Also assumed for peers until their first ACK
+  pub max_datagram_size: usize, // Bigger INcome datagrams are rejected before any of their segments is kept
+  pub max_pending_in: usize, // INcome datagrams being reassembled at once, the least recently active one is evicted to make room
+  pub max_pending_in_per_peer: usize, // Same for a single peer, its new datagrams are rejected once it has that many
+  pub channels: HashMap<u8, ChannelMode>, // Channels missing here, 0 (used by `send`) included, are reliable unordered
+  pub accept_sessions: bool, // Whether CONNECTs from peers are accepted, refused otherwise
+  pub session_backlog: usize, // Accepted sessions waiting for `accept` before new CONNECTs are refused
+  pub handshake_timeout: Duration, // How long `connect` and `AckUdpSession::close` wait for the peer to answer
+  pub keepalive_interval: Duration, // Peers with pending datagrams or a session are pinged after being quiet this long
+  pub peer_timeout: Duration, // Such a peer is considered dead after being quiet this long
+  pub psk: Option<PreSharedKey>, // Every packet is encrypted and authenticated with it, peers need the same one
+  pub noise_keypair: Option<NoiseKeypair>, // Static key for Noise handshakes with each peer, packets are then encrypted with per-peer keys. Exclusive with `psk`
+  pub cookie_challenge: bool, // Segments of multi-segment datagrams from unvalidated addresses are answered with a cookie to echo instead of being reassembled
+  pub amplification_factor: usize, // Replies to an unvalidated address are at most this many times bigger than the packet they answer. Always applies to Noise handshakes, to everything else with `cookie_challenge`
+  pub rate_limit: Option<RateLimit>, // Packets accepted per source IP, the excess is discarded before it's decoded
+  pub ban_threshold: u32, // Malformed or rate limited packets from one IP within `ban_window` that get it banned, 0 never bans
+  pub ban_window: Duration,
+  pub ban_duration: Duration, // How long everything from a banned IP is discarded
+}
+
+impl Default for AckUdpConfig {
+  fn default() -> Self {
+    AckUdpConfig {
+      segment_size: 400,
+      recv_buffer_size: 433,
+      resend_interval: Duration::from_millis(500),
+      min_rto: Duration::from_millis(50),
+      max_rto: Duration::from_secs(2),
+      timer_granularity: Duration::from_millis(10),
+      max_resend_attempts: 200,
+      incoming_expiry: Duration::from_secs(30),
+      incoming_queue_capacity: 1024,
+      dedup_window_size: 1024,
+      dedup_window_ttl: Duration::from_secs(400),
+      congestion_control: CongestionControl::NewReno,
+      receive_window: 8 * 1024 * 1024,
+      max_datagram_size: 8 * 1024 * 1024,
+      max_pending_in: 1024,
+      max_pending_in_per_peer: 64,
+      channels: HashMap::new(),
+      accept_sessions: false,
+      session_backlog: 128,
+      handshake_timeout: Duration::from_secs(5),
+      keepalive_interval: Duration::from_secs(5),
+      peer_timeout: Duration::from_secs(30),
+      psk: None,
+      noise_keypair: None,
+      cookie_challenge: false,
+      amplification_factor: 3,
+      rate_limit: None,
+      ban_threshold: 0,
+      ban_window: Duration::from_secs(10),
+      ban_duration: Duration::from_secs(60)
+    }
+  }
+}
+
+impl AckUdpConfig {
+  pub fn channel_mode(&self, channel: u8) -> ChannelMode {
+    self.channels.get(&channel).copied().unwrap_or_default()
+  }
+
+  // Biggest packet on the wire
+  pub fn packet_size(&self) -> usize {
+    if self.noise_keypair.is_some() {
+      return (HEADER_SIZE + self.segment_size + ENCRYPTION_OVERHEAD).max(HEADER_SIZE + MAX_HANDSHAKE_MESSAGE);
+    }
+    let overhead = if self.psk.is_some() { ENCRYPTION_OVERHEAD } else { 0 };
+
+    HEADER_SIZE + self.segment_size + overhead
+  }
+
+  pub fn is_encrypted(&self) -> bool {
+    self.psk.is_some() || self.noise_keypair.is_some()
+  }
+
+  pub fn validate(&self) -> io::Result<()> {
+    // An ACK carries the window, the cumulative ACK and at least one SACK range, all within one segment
+    if self.segment_size < MIN_SEGMENT_SIZE || self.segment_size > u16::MAX as usize {
+      return Err(invalid(format!("segment_size must be in {MIN_SEGMENT_SIZE}..={}", u16::MAX)));
+    }
+    if self.recv_buffer_size < self.packet_size() {
+      return Err(invalid(format!(
+        "recv_buffer_size ({}) can't fit a {HEADER_SIZE} bytes header, a {} bytes segment and the encryption overhead if any ({} needed)",
+        self.recv_buffer_size, self.segment_size, self.packet_size()
+      )));
+    }
+    if self.amplification_factor == 0 {
+      return Err(invalid("amplification_factor must be greater than zero".into()));
+    }
+    if self.rate_limit.is_some_and(|rate_limit| rate_limit.packets_per_second == 0 || rate_limit.burst == 0) {
+      return Err(invalid("rate_limit needs packets_per_second and burst greater than zero".into()));
+    }
+    if self.ban_window.is_zero() || self.ban_duration.is_zero() {
+      return Err(invalid("ban_window and ban_duration must be greater than zero".into()));
+    }
+    if self.psk.is_some() && self.noise_keypair.is_some() {
+      return Err(invalid("psk and noise_keypair can't be used together".into()));
+    }
+    if self.is_encrypted() && self.segment_size + ENCRYPTION_OVERHEAD > u16::MAX as usize {
+      return Err(invalid(format!("segment_size must not exceed {} with encryption", u16::MAX as usize - ENCRYPTION_OVERHEAD)));
+    }
+    if self.resend_interval.is_zero() {
+      return Err(invalid("resend_interval must be greater than zero".into()));
+    }
+    if self.min_rto.is_zero() || self.min_rto > self.resend_interval || self.resend_interval > self.max_rto {
+      return Err(invalid("expected 0 < min_rto <= resend_interval <= max_rto".into()));
+    }
+    if self.timer_granularity.is_zero() || self.timer_granularity > self.min_rto {
+      return Err(invalid("timer_granularity must be greater than zero and not exceed min_rto".into()));
+    }
+    if self.max_resend_attempts == 0 {
+      return Err(invalid("max_resend_attempts must be greater than zero".into()));
+    }
+    if self.incoming_expiry <= self.max_rto {
+      // Otherwise the receiver forgets a datagram before the sender gets a chance to resend its segments
+      return Err(invalid("incoming_expiry must be greater than max_rto".into()));
+    }
+    if self.incoming_queue_capacity == 0 {
+      return Err(invalid("incoming_queue_capacity must be greater than zero".into()));
+    }
+    if self.dedup_window_size == 0 {
+      return Err(invalid("dedup_window_size must be greater than zero".into()));
+    }
+    if self.dedup_window_ttl < self.max_rto.saturating_mul(u32::from(self.max_resend_attempts)) {
+      // Shorter would forget ids while the sender may still retransmit them, duplicates would then be delivered again
+      return Err(invalid("dedup_window_ttl must be at least max_rto * max_resend_attempts".into()));
+    }
+    if self.receive_window < self.segment_size || self.receive_window > u32::MAX as usize {
+      return Err(invalid(format!("receive_window must be between segment_size and {}", u32::MAX)));
+    }
+    if self.max_datagram_size < self.segment_size {
+      return Err(invalid("max_datagram_size must not be smaller than segment_size".into()));
+    }
+    if self.max_pending_in_per_peer == 0 || self.max_pending_in < self.max_pending_in_per_peer {
+      return Err(invalid("expected 0 < max_pending_in_per_peer <= max_pending_in".into()));
+    }
+    if !self.channel_mode(0).is_reliable() {
+      return Err(invalid("channel 0 is used by `send` and has to be reliable".into()));
+    }
+    if let CongestionControl::FixedRate { bytes_per_second: 0 } = self.congestion_control {
+      return Err(invalid("FixedRate congestion control needs bytes_per_second greater than zero".into()));
+    }
+    if self.session_backlog == 0 {
+      return Err(invalid("session_backlog must be greater than zero".into()));
+    }
+    if self.handshake_timeout.is_zero() {
+      return Err(invalid("handshake_timeout must be greater than zero".into()));
+    }
+    if self.keepalive_interval.is_zero() || self.peer_timeout <= self.keepalive_interval {
+      // Otherwise a quiet peer is declared dead before it got a single ping
+      return Err(invalid("expected 0 < keepalive_interval < peer_timeout".into()));
+    }
+
+    Ok(())
+  }
+}
+
+fn invalid(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AckUdpBuilder {
+  config: AckUdpConfig
+}
+
+impl AckUdpBuilder {
+  pub fn new() -> Self {
+    AckUdpBuilder::default()
+  }
+
+  pub fn config(mut self, config: AckUdpConfig) -> Self {
+    self.config = config;
+    self
+  }
+
+  // Grows `recv_buffer_size` if it can't fit the new segment size
+  pub fn segment_size(mut self, segment_size: usize) -> Self {
+    self.config.segment_size = segment_size;
+    self.config.recv_buffer_size = self.config.recv_buffer_size.max(self.config.packet_size());
+    self
+  }
+
+  pub fn recv_buffer_size(mut self, recv_buffer_size: usize) -> Self {
+    self.config.recv_buffer_size = recv_buffer_size;
+    self
+  }
+
+  pub fn resend_interval(mut self, resend_interval: Duration) -> Self {
+    self.config.resend_interval = resend_interval;
+    self
+  }
+
+  pub fn min_rto(mut self, min_rto: Duration) -> Self {
+    self.config.min_rto = min_rto;
+    self
+  }
+
+  pub fn max_rto(mut self, max_rto: Duration) -> Self {
+    self.config.max_rto = max_rto;
+    self
+  }
+
+  pub fn timer_granularity(mut self, timer_granularity: Duration) -> Self {
+    self.config.timer_granularity = timer_granularity;
+    self
+  }
+
+  pub fn max_resend_attempts(mut self, max_resend_attempts: u16) -> Self {
+    self.config.max_resend_attempts = max_resend_attempts;
+    self
+  }
+
+  pub fn incoming_expiry(mut self, incoming_expiry: Duration) -> Self {
+    self.config.incoming_expiry = incoming_expiry;
+    self
+  }
+
+  pub fn incoming_queue_capacity(mut self, incoming_queue_capacity: usize) -> Self {
+    self.config.incoming_queue_capacity = incoming_queue_capacity;
+    self
+  }
+
+  pub fn dedup_window_size(mut self, dedup_window_size: usize) -> Self {
+    self.config.dedup_window_size = dedup_window_size;
+    self
+  }
+
+  pub fn dedup_window_ttl(mut self, dedup_window_ttl: Duration) -> Self {
+    self.config.dedup_window_ttl = dedup_window_ttl;
+    self
+  }
+
+  pub fn congestion_control(mut self, congestion_control: CongestionControl) -> Self {
+    self.config.congestion_control = congestion_control;
+    self
+  }
+
+  pub fn receive_window(mut self, receive_window: usize) -> Self {
+    self.config.receive_window = receive_window;
+    self
+  }
+
+  pub fn max_datagram_size(mut self, max_datagram_size: usize) -> Self {
+    self.config.max_datagram_size = max_datagram_size;
+    self
+  }
+
+  pub fn max_pending_in(mut self, max_pending_in: usize) -> Self {
+    self.config.max_pending_in = max_pending_in;
+    self
+  }
+
+  pub fn max_pending_in_per_peer(mut self, max_pending_in_per_peer: usize) -> Self {
+    self.config.max_pending_in_per_peer = max_pending_in_per_peer;
+    self
+  }
+
+  pub fn channel(mut self, channel: u8, mode: ChannelMode) -> Self {
+    self.config.channels.insert(channel, mode);
+    self
+  }
+
+  pub fn accept_sessions(mut self, accept_sessions: bool) -> Self {
+    self.config.accept_sessions = accept_sessions;
+    self
+  }
+
+  pub fn session_backlog(mut self, session_backlog: usize) -> Self {
+    self.config.session_backlog = session_backlog;
+    self
+  }
+
+  pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> Self {
+    self.config.handshake_timeout = handshake_timeout;
+    self
+  }
+
+  pub fn keepalive_interval(mut self, keepalive_interval: Duration) -> Self {
+    self.config.keepalive_interval = keepalive_interval;
+    self
+  }
+
+  pub fn peer_timeout(mut self, peer_timeout: Duration) -> Self {
+    self.config.peer_timeout = peer_timeout;
+    self
+  }
+
+  // Grows `recv_buffer_size` by the encryption overhead if needed
+  pub fn psk(mut self, psk: [u8; 32]) -> Self {
+    self.config.psk = Some(PreSharedKey(psk));
+    self.config.recv_buffer_size = self.config.recv_buffer_size.max(self.config.packet_size());
+    self
+  }
+
+  // Grows `recv_buffer_size` by the encryption overhead if needed
+  pub fn noise_keypair(mut self, keypair: NoiseKeypair) -> Self {
+    self.config.noise_keypair = Some(keypair);
+    self.config.recv_buffer_size = self.config.recv_buffer_size.max(self.config.packet_size());
+    self
+  }
+
+  pub fn cookie_challenge(mut self, cookie_challenge: bool) -> Self {
+    self.config.cookie_challenge = cookie_challenge;
+    self
+  }
+
+  pub fn amplification_factor(mut self, amplification_factor: usize) -> Self {
+    self.config.amplification_factor = amplification_factor;
+    self
+  }
+
+  pub fn rate_limit(mut self, packets_per_second: u32, burst: u32) -> Self {
+    self.config.rate_limit = Some(RateLimit { packets_per_second, burst });
+    self
+  }
+
+  pub fn ban_threshold(mut self, ban_threshold: u32) -> Self {
+    self.config.ban_threshold = ban_threshold;
+    self
+  }
+
+  pub fn ban_window(mut self, ban_window: Duration) -> Self {
+    self.config.ban_window = ban_window;
+    self
+  }
+
+  pub fn ban_duration(mut self, ban_duration: Duration) -> Self {
+    self.config.ban_duration = ban_duration;
+    self
+  }
+
+  pub fn build(self) -> io::Result<AckUdpConfig> {
+    self.config.validate()?;
+
+    Ok(self.config)
+  }
+
+  pub async fn bind(self, address: SocketAddr) -> io::Result<AckUdp> {
+    AckUdp::with_config(address, self.build()?).await
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn defaults_are_valid() {
+    assert!(AckUdpBuilder::new().build().is_ok());
+  }
+
+  #[test]
+  fn rejects_inconsistent_timers() {
+    let builder = AckUdpBuilder::new;
+    assert!(builder().min_rto(Duration::from_secs(2)).resend_interval(Duration::from_secs(1)).build().is_err());
+    assert!(builder().resend_interval(Duration::ZERO).build().is_err());
+    assert!(builder().incoming_expiry(Duration::from_secs(1)).max_rto(Duration::from_secs(1)).build().is_err());
+  }
+
+  #[test]
+  fn rejects_segments_that_dont_fit() {
+    assert!(AckUdpBuilder::new().segment_size(MIN_SEGMENT_SIZE - 1).build().is_err());
+    assert!(AckUdpBuilder::new().segment_size(1000).recv_buffer_size(HEADER_SIZE + 999).build().is_err());
+    assert!(AckUdpBuilder::new().segment_size(1000).recv_buffer_size(HEADER_SIZE + 1000).build().is_ok());
+  }
+}
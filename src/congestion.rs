@@ -0,0 +1,222 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::AckUdpConfig;
+
+// Decides how much of a peer's OUTcome data may be on the wire at once.
+// `bytes` are always segment payload sizes.
+pub trait CongestionController: Send + fmt::Debug {
+  // Whether one more segment of `bytes` may be sent while `bytes_in_flight` are still unACKed
+  fn can_send(&mut self, bytes_in_flight: usize, bytes: usize) -> bool;
+
+  fn on_sent(&mut self, _bytes: usize) {}
+
+  // `bytes` got ACKed for the first time
+  fn on_ack(&mut self, bytes: usize);
+
+  // A loss was detected without waiting for the retransmission timeout
+  fn on_loss(&mut self, bytes_in_flight: usize);
+
+  // The retransmission timeout expired, everything in flight is considered lost
+  fn on_timeout(&mut self);
+
+  // The peer's smoothed RTT changed
+  fn on_rtt(&mut self, _srtt: Duration) {}
+
+  // Current congestion window in bytes, for reporting only
+  fn window(&self) -> usize;
+}
+
+pub type CongestionControllerFactory = Arc<dyn Fn(&AckUdpConfig) -> Box<dyn CongestionController> + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub enum CongestionControl {
+  #[default]
+  NewReno,
+  FixedRate { bytes_per_second: usize },
+  Custom(CongestionControllerFactory),
+}
+
+impl CongestionControl {
+  pub fn build(&self, config: &AckUdpConfig) -> Box<dyn CongestionController> {
+    match self {
+      CongestionControl::NewReno => Box::new(NewReno::new(config.segment_size)),
+      CongestionControl::FixedRate { bytes_per_second } => Box::new(FixedRate::new(*bytes_per_second, config.segment_size)),
+      CongestionControl::Custom(factory) => factory(config),
+    }
+  }
+}
+
+impl fmt::Debug for CongestionControl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CongestionControl::NewReno => write!(f, "NewReno"),
+      CongestionControl::FixedRate { bytes_per_second } => f.debug_struct("FixedRate").field("bytes_per_second", bytes_per_second).finish(),
+      CongestionControl::Custom(_) => write!(f, "Custom"),
+    }
+  }
+}
+
+// Slow start followed by additive increase / multiplicative decrease, as in TCP NewReno
+#[derive(Debug)]
+pub struct NewReno {
+  mss: usize,
+  cwnd: usize,
+  ssthresh: usize,
+  recovery_bytes_left: usize, // Bytes to be ACKed before another loss may shrink the window again
+}
+
+impl NewReno {
+  pub fn new(mss: usize) -> Self {
+    NewReno { mss, cwnd: 10 * mss, ssthresh: usize::MAX, recovery_bytes_left: 0 }
+  }
+}
+
+impl CongestionController for NewReno {
+  fn can_send(&mut self, bytes_in_flight: usize, bytes: usize) -> bool {
+    // Always let one segment out, otherwise nothing would ever be ACKed again
+    bytes_in_flight == 0 || bytes_in_flight + bytes <= self.cwnd
+  }
+
+  fn on_ack(&mut self, bytes: usize) {
+    if self.recovery_bytes_left > 0 {
+      self.recovery_bytes_left = self.recovery_bytes_left.saturating_sub(bytes);
+      return;
+    }
+
+    if self.cwnd < self.ssthresh {
+      self.cwnd += bytes;
+    }
+    else {
+      self.cwnd += (self.mss * bytes / self.cwnd).max(1);
+    }
+  }
+
+  fn on_loss(&mut self, bytes_in_flight: usize) {
+    if self.recovery_bytes_left > 0 {
+      return;
+    }
+
+    self.ssthresh = (bytes_in_flight / 2).max(2 * self.mss);
+    self.cwnd = self.ssthresh;
+    self.recovery_bytes_left = bytes_in_flight.max(self.mss);
+  }
+
+  fn on_timeout(&mut self) {
+    self.ssthresh = (self.cwnd / 2).max(2 * self.mss);
+    self.cwnd = self.mss;
+    self.recovery_bytes_left = 0;
+  }
+
+  fn window(&self) -> usize {
+    self.cwnd
+  }
+}
+
+// Sends at a constant rate regardless of ACKs and losses (token bucket, bursts up to one segment)
+#[derive(Debug)]
+pub struct FixedRate {
+  bytes_per_second: usize,
+  burst: usize,
+  tokens: f64,
+  last_refill: Instant,
+  srtt: Option<Duration>,
+}
+
+impl FixedRate {
+  pub fn new(bytes_per_second: usize, mss: usize) -> Self {
+    FixedRate { bytes_per_second, burst: mss, tokens: mss as f64, last_refill: Instant::now(), srtt: None }
+  }
+
+  fn refill(&mut self) {
+    let elapsed = self.last_refill.elapsed();
+    self.last_refill = Instant::now();
+    self.tokens = (self.tokens + elapsed.as_secs_f64() * self.bytes_per_second as f64).min(self.burst as f64);
+  }
+}
+
+impl CongestionController for FixedRate {
+  fn can_send(&mut self, _bytes_in_flight: usize, bytes: usize) -> bool {
+    self.refill();
+    self.tokens >= bytes.min(self.burst) as f64
+  }
+
+  fn on_sent(&mut self, bytes: usize) {
+    self.tokens -= bytes as f64;
+  }
+
+  fn on_ack(&mut self, _bytes: usize) {}
+
+  fn on_loss(&mut self, _bytes_in_flight: usize) {}
+
+  fn on_timeout(&mut self) {}
+
+  fn on_rtt(&mut self, srtt: Duration) {
+    self.srtt = Some(srtt);
+  }
+
+  // What the rate puts on the wire in one round trip, at least one segment
+  fn window(&self) -> usize {
+    let per_round_trip = self.srtt.map_or(0.0, |srtt| srtt.as_secs_f64() * self.bytes_per_second as f64);
+
+    (per_round_trip as usize).max(self.burst)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const MSS: usize = 100;
+
+  #[test]
+  fn new_reno_grows_exponentially_then_linearly() {
+    let mut new_reno = NewReno::new(MSS);
+    assert!(new_reno.can_send(0, 100 * MSS));
+    assert!(!new_reno.can_send(10 * MSS, MSS));
+
+    new_reno.on_ack(MSS);
+    assert_eq!(new_reno.window(), 11 * MSS);
+
+    new_reno.on_loss(20 * MSS);
+    assert_eq!(new_reno.window(), 10 * MSS);
+    // Nothing grows until the bytes in flight at the loss are ACKed, then one segment per window
+    new_reno.on_ack(20 * MSS);
+    new_reno.on_ack(10 * MSS);
+    assert_eq!(new_reno.window(), 11 * MSS);
+  }
+
+  #[test]
+  fn new_reno_shrinks_once_per_recovery() {
+    let mut new_reno = NewReno::new(MSS);
+    new_reno.on_loss(8 * MSS);
+    new_reno.on_loss(4 * MSS);
+    assert_eq!(new_reno.window(), 4 * MSS);
+    new_reno.on_ack(4 * MSS);
+    assert_eq!(new_reno.window(), 4 * MSS);
+
+    new_reno.on_timeout();
+    assert_eq!(new_reno.window(), MSS);
+    new_reno.on_loss(MSS);
+    assert_eq!(new_reno.window(), 2 * MSS);
+  }
+
+  #[test]
+  fn fixed_rate_allows_one_segment_at_a_time() {
+    let mut fixed_rate = FixedRate::new(1, MSS);
+    assert!(fixed_rate.can_send(0, MSS));
+    fixed_rate.on_sent(MSS);
+    assert!(!fixed_rate.can_send(0, MSS));
+  }
+
+  #[test]
+  fn fixed_rate_window_covers_a_round_trip() {
+    let mut fixed_rate = FixedRate::new(100_000, MSS);
+    assert_eq!(fixed_rate.window(), MSS);
+    fixed_rate.on_rtt(Duration::from_millis(50));
+    assert_eq!(fixed_rate.window(), 5_000);
+    fixed_rate.on_rtt(Duration::from_micros(100));
+    assert_eq!(fixed_rate.window(), MSS);
+  }
+}
@@ -0,0 +1,103 @@
+use std::net::{IpAddr, SocketAddr};
+
+use byteorder::{BigEndian, ByteOrder};
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use tokio::time::Instant;
+
+const MAC_SIZE: usize = 16;
+// 4 bytes issue time (seconds since the jar was created)   16 bytes truncated HMAC-SHA256 of it and the address
+pub const COOKIE_SIZE: usize = 4 + MAC_SIZE;
+// Seconds a cookie is accepted after being issued
+const COOKIE_LIFETIME: u32 = 60;
+
+// Stateless return routability check: a cookie proves its sender gets what we send to its address.
+// Nothing is stored per address, the cookie is recomputed from a secret when it's echoed back.
+pub struct CookieJar {
+  secret: [u8; 32],
+  created_at: Instant,
+}
+
+impl Default for CookieJar {
+  fn default() -> Self {
+    CookieJar { secret: rand::thread_rng().gen(), created_at: Instant::now() }
+  }
+}
+
+impl CookieJar {
+  pub fn issue(&self, address: SocketAddr) -> Vec<u8> {
+    let issued_at = self.now();
+    let mut cookie = issued_at.to_be_bytes().to_vec();
+    cookie.extend_from_slice(&self.mac(address, issued_at).finalize().into_bytes()[..MAC_SIZE]);
+
+    cookie
+  }
+
+  pub fn verify(&self, address: SocketAddr, cookie: &[u8]) -> bool {
+    if cookie.len() != COOKIE_SIZE {
+      return false;
+    }
+
+    let issued_at = BigEndian::read_u32(&cookie[..4]);
+    // Cookies from the future wrap around to a huge age
+    if self.now().wrapping_sub(issued_at) > COOKIE_LIFETIME {
+      return false;
+    }
+
+    self.mac(address, issued_at).verify_truncated_left(&cookie[4..]).is_ok()
+  }
+
+  fn now(&self) -> u32 {
+    self.created_at.elapsed().as_secs() as u32
+  }
+
+  fn mac(&self, address: SocketAddr, issued_at: u32) -> Hmac<sha2::Sha256> {
+    let mut mac = Hmac::<sha2::Sha256>::new_from_slice(&self.secret).expect("HMAC takes keys of any size");
+    mac.update(&issued_at.to_be_bytes());
+    match address.ip() {
+      IpAddr::V4(ip) => mac.update(&ip.octets()),
+      IpAddr::V6(ip) => mac.update(&ip.octets())
+    }
+    mac.update(&address.port().to_be_bytes());
+
+    mac
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn address(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  #[test]
+  fn cookies_are_bound_to_the_address() {
+    let jar = CookieJar::default();
+    let cookie = jar.issue(address(1));
+    assert_eq!(cookie.len(), COOKIE_SIZE);
+    assert!(jar.verify(address(1), &cookie));
+    assert!(!jar.verify(address(2), &cookie));
+    assert!(!jar.verify(SocketAddr::from(([127, 0, 0, 2], 1)), &cookie));
+    // Another jar has another secret
+    assert!(!CookieJar::default().verify(address(1), &cookie));
+  }
+
+  #[test]
+  fn rejects_altered_cookies() {
+    let jar = CookieJar::default();
+    let cookie = jar.issue(address(1));
+    assert!(!jar.verify(address(1), &cookie[..COOKIE_SIZE - 1]));
+    assert!(!jar.verify(address(1), &[cookie.as_slice(), &[0]].concat()));
+
+    let mut tampered = cookie.clone();
+    tampered[COOKIE_SIZE - 1] ^= 1;
+    assert!(!jar.verify(address(1), &tampered));
+
+    // Moving the issue time forward doesn't make it last longer
+    let mut postdated = cookie.clone();
+    postdated[..4].copy_from_slice(&1000u32.to_be_bytes());
+    assert!(!jar.verify(address(1), &postdated));
+  }
+}
@@ -0,0 +1,288 @@
+use std::{collections::HashMap, fmt, sync::atomic::{AtomicU64, Ordering}};
+
+use byteorder::{BigEndian, ByteOrder};
+use chacha20poly1305::{aead::{Aead, Payload}, Key, KeyInit, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::{types::{fill_checksum, AckUdpPacket, PacketFlags, HEADER_SIZE}, AckUdpError};
+
+// Sender id (8 bytes) and counter (8 bytes), the rest of the 24 bytes XChaCha20 nonce is zero
+pub const NONCE_SIZE: usize = 16;
+pub const TAG_SIZE: usize = 16;
+// Added to every sealed payload
+pub const ENCRYPTION_OVERHEAD: usize = NONCE_SIZE + TAG_SIZE;
+// Counters this far behind the highest one seen are refused even if they weren't seen
+const REPLAY_WINDOW: u64 = 1024;
+// Senders a replay window is kept for, the one heard from least recently is forgotten to make room
+const MAX_SENDERS: usize = 4096;
+
+// 32 bytes key shared by every side out of band. Kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey(pub [u8; 32]);
+
+impl fmt::Debug for PreSharedKey {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "PreSharedKey(..)")
+  }
+}
+
+// Where the key to open a packet comes from
+enum OpeningKey {
+  Shared(PreSharedKey), // Each sender seals with its own key derived from this one and its id
+  Peer(XChaCha20Poly1305), // Agreed on with the only peer that seals for us with it
+}
+
+// XChaCha20-Poly1305 over packet payloads. The nonce is the random id of whoever sealed the packet and
+// a counter, which receivers check against a replay window per sender id. A packet is opened at most once,
+// whichever address it comes from.
+pub struct PacketCipher {
+  sender_id: [u8; 8], // Picked anew for every socket, so a restarted sender doesn't collide with its old counters
+  counter: AtomicU64,
+  sealing: XChaCha20Poly1305,
+  opening: OpeningKey,
+  windows: Mutex<HashMap<[u8; 8], ReplayWindow>>, // By sender id
+}
+
+impl PacketCipher {
+  // Every direction gets its own key: each sender derives it from `key` and its id
+  pub fn new(key: &PreSharedKey) -> Self {
+    let sender_id = rand::thread_rng().gen();
+
+    PacketCipher::with_keys(sender_id, derive_key(key, &sender_id), OpeningKey::Shared(key.clone()))
+  }
+
+  // Separate keys for each direction
+  pub fn from_keys(sealing: &[u8; 32], opening: &[u8; 32]) -> Self {
+    let opening = OpeningKey::Peer(XChaCha20Poly1305::new(Key::from_slice(opening)));
+
+    PacketCipher::with_keys(rand::thread_rng().gen(), XChaCha20Poly1305::new(Key::from_slice(sealing)), opening)
+  }
+
+  fn with_keys(sender_id: [u8; 8], sealing: XChaCha20Poly1305, opening: OpeningKey) -> Self {
+    PacketCipher { sender_id, counter: AtomicU64::new(0), sealing, opening, windows: Mutex::new(HashMap::new()) }
+  }
+
+  // Takes an encoded packet and returns it with the payload sealed. The header is authenticated along with it,
+  // so neither of them can be forged or changed without the key.
+  pub fn seal(&self, mut raw_packet: Vec<u8>) -> Vec<u8> {
+    let plaintext = raw_packet.split_off(HEADER_SIZE);
+    let mut header = raw_packet;
+
+    let flags = BigEndian::read_u16(&header[7..9]) | PacketFlags::ENCRYPTED.bits();
+    BigEndian::write_u16(&mut header[7..9], flags);
+    BigEndian::write_u16(&mut header[HEADER_SIZE - 2..], (plaintext.len() + ENCRYPTION_OVERHEAD) as u16);
+
+    // Starts at 1, 0 is never valid
+    let counter = self.counter.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut nonce = self.sender_id.to_vec();
+    nonce.extend_from_slice(&counter.to_be_bytes());
+    let sealed = self.sealing.encrypt(&xnonce(&nonce), Payload { msg: &plaintext, aad: &aad(&header) })
+      .expect("payload fits into one packet");
+
+    header.extend_from_slice(&nonce);
+    header.extend_from_slice(&sealed);
+    fill_checksum(&mut header);
+
+    header
+  }
+
+  // Replaces the sealed payload of a decoded `packet` with the plaintext. `raw_packet` is what it was decoded from.
+  pub fn open(&self, packet: &mut AckUdpPacket, raw_packet: &[u8]) -> Result<(), AckUdpError> {
+    if !packet.flags.contains(PacketFlags::ENCRYPTED) || packet.payload.len() < ENCRYPTION_OVERHEAD {
+      return Err(AckUdpError::Unauthenticated);
+    }
+
+    let (nonce, sealed) = packet.payload.split_at(NONCE_SIZE);
+    let sender_id: [u8; 8] = nonce[..8].try_into().expect("nonce starts with the sender id");
+    let counter = BigEndian::read_u64(&nonce[8..]);
+    // Our own packets reflected back to us
+    if sender_id == self.sender_id || counter == 0 {
+      return Err(AckUdpError::Replayed);
+    }
+    // Checked before decrypting so replays cost as little as possible, and again once the packet proved authentic
+    if !self.windows.lock().get(&sender_id).is_none_or(|window| window.is_fresh(counter)) {
+      return Err(AckUdpError::Replayed);
+    }
+
+    let payload = Payload { msg: sealed, aad: &aad(&raw_packet[..HEADER_SIZE]) };
+    let plaintext = match &self.opening {
+      OpeningKey::Shared(key) => derive_key(key, &sender_id).decrypt(&xnonce(nonce), payload),
+      OpeningKey::Peer(cipher) => cipher.decrypt(&xnonce(nonce), payload)
+    }.map_err(|_| AckUdpError::Unauthenticated)?;
+
+    if !self.mark(sender_id, counter) {
+      return Err(AckUdpError::Replayed);
+    }
+
+    packet.flags.remove(PacketFlags::ENCRYPTED);
+    packet.payload_size = plaintext.len() as u16;
+    packet.payload = plaintext;
+
+    Ok(())
+  }
+
+  // Only authentic packets get a sender a window, so nobody without the key can make us keep any
+  fn mark(&self, sender_id: [u8; 8], counter: u64) -> bool {
+    let mut windows = self.windows.lock();
+    if !windows.contains_key(&sender_id) && windows.len() >= MAX_SENDERS {
+      let oldest = windows.iter().min_by_key(|(_, window)| window.last_seen).map(|(id, _)| *id);
+      if let Some(oldest) = oldest {
+        windows.remove(&oldest);
+      }
+    }
+
+    windows.entry(sender_id).or_default().mark(counter)
+  }
+}
+
+// Sliding window over the counters of one sender: the highest one seen and which of the ones below it were seen too
+#[derive(Debug)]
+struct ReplayWindow {
+  highest: u64,
+  seen: [u64; REPLAY_WINDOW as usize / 64], // Bit `counter % REPLAY_WINDOW`
+  last_seen: Instant,
+}
+
+impl Default for ReplayWindow {
+  fn default() -> Self {
+    ReplayWindow { highest: 0, seen: [0; REPLAY_WINDOW as usize / 64], last_seen: Instant::now() }
+  }
+}
+
+impl ReplayWindow {
+  fn is_fresh(&self, counter: u64) -> bool {
+    if counter > self.highest {
+      return true;
+    }
+
+    self.highest - counter < REPLAY_WINDOW && !self.is_seen(counter)
+  }
+
+  // Returns whether `counter` was fresh
+  fn mark(&mut self, counter: u64) -> bool {
+    if !self.is_fresh(counter) {
+      return false;
+    }
+
+    if counter > self.highest {
+      // Counters that slid out of the window make room for the new ones
+      if counter - self.highest >= REPLAY_WINDOW {
+        self.seen = [0; REPLAY_WINDOW as usize / 64];
+      }
+      else {
+        for skipped in self.highest + 1..counter {
+          let (word, bit) = ReplayWindow::position(skipped);
+          self.seen[word] &= !bit;
+        }
+      }
+      self.highest = counter;
+    }
+
+    let (word, bit) = ReplayWindow::position(counter);
+    self.seen[word] |= bit;
+    self.last_seen = Instant::now();
+
+    true
+  }
+
+  fn is_seen(&self, counter: u64) -> bool {
+    let (word, bit) = ReplayWindow::position(counter);
+
+    self.seen[word] & bit != 0
+  }
+
+  fn position(counter: u64) -> (usize, u64) {
+    let index = counter % REPLAY_WINDOW;
+
+    ((index / 64) as usize, 1 << (index % 64))
+  }
+}
+
+// Key a sender with `sender_id` seals with under the pre-shared `key`
+fn derive_key(key: &PreSharedKey, sender_id: &[u8; 8]) -> XChaCha20Poly1305 {
+  let mut mac = <Hmac<sha2::Sha256> as Mac>::new_from_slice(&key.0).expect("HMAC takes keys of any size");
+  mac.update(b"ack-udp sender key");
+  mac.update(sender_id);
+
+  XChaCha20Poly1305::new(&mac.finalize().into_bytes())
+}
+
+fn xnonce(nonce: &[u8]) -> XNonce {
+  let mut xnonce = XNonce::default();
+  xnonce[..NONCE_SIZE].copy_from_slice(nonce);
+
+  xnonce
+}
+
+// The header without its checksum, which is computed after sealing
+fn aad(header: &[u8]) -> Vec<u8> {
+  let mut aad = header[..HEADER_SIZE].to_vec();
+  aad[3..7].fill(0);
+
+  aad
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sealed(cipher: &PacketCipher, payload: &[u8]) -> Vec<u8> {
+    let mut segments = AckUdpPacket::split(payload, payload.len().max(1), [1; 5], 0, (0, 0), PacketFlags::empty());
+
+    cipher.seal(segments.remove(&0).unwrap().into())
+  }
+
+  fn open(cipher: &PacketCipher, raw_packet: &[u8]) -> Result<Vec<u8>, AckUdpError> {
+    let mut packet = AckUdpPacket::try_from(raw_packet)?;
+    cipher.open(&mut packet, raw_packet)?;
+
+    Ok(packet.payload)
+  }
+
+  #[test]
+  fn opens_what_a_peer_with_the_same_key_sealed() {
+    let (a, b) = (PacketCipher::new(&PreSharedKey([7; 32])), PacketCipher::new(&PreSharedKey([7; 32])));
+    let raw_packet = sealed(&a, b"secret");
+    assert_eq!(raw_packet.len(), HEADER_SIZE + 6 + ENCRYPTION_OVERHEAD);
+    assert_eq!(open(&b, &raw_packet).unwrap(), b"secret");
+
+    let stranger = PacketCipher::new(&PreSharedKey([8; 32]));
+    assert!(matches!(open(&stranger, &sealed(&a, b"secret")), Err(AckUdpError::Unauthenticated)));
+  }
+
+  #[test]
+  fn header_is_authenticated() {
+    let (a, b) = (PacketCipher::new(&PreSharedKey([7; 32])), PacketCipher::new(&PreSharedKey([7; 32])));
+    let mut raw_packet = sealed(&a, b"secret");
+    // Another channel, with a checksum that matches again
+    raw_packet[HEADER_SIZE - 3] = 5;
+    fill_checksum(&mut raw_packet);
+    assert!(matches!(open(&b, &raw_packet), Err(AckUdpError::Unauthenticated)));
+  }
+
+  #[test]
+  fn packets_are_opened_once() {
+    let (a, b) = (PacketCipher::new(&PreSharedKey([7; 32])), PacketCipher::new(&PreSharedKey([7; 32])));
+    let first = sealed(&a, b"first");
+    let second = sealed(&a, b"second");
+    // Out of order is fine, twice isn't
+    assert!(open(&b, &second).is_ok());
+    assert!(open(&b, &first).is_ok());
+    assert!(matches!(open(&b, &first), Err(AckUdpError::Replayed)));
+    // Nor are our own packets sent back to us
+    assert!(matches!(open(&a, &sealed(&a, b"mine")), Err(AckUdpError::Replayed)));
+  }
+
+  #[test]
+  fn replay_window_slides() {
+    let mut window = ReplayWindow::default();
+    assert!(window.mark(5) && window.mark(3) && !window.mark(3));
+    assert!(window.mark(2000));
+    assert!(!window.is_fresh(2000 - REPLAY_WINDOW));
+    assert!(window.is_fresh(2000 - REPLAY_WINDOW + 1) && window.mark(1500));
+    assert!(!window.mark(1500) && !window.mark(5));
+  }
+}
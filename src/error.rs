@@ -0,0 +1,47 @@
+use std::{error::Error, fmt, io};
+
+#[derive(Debug)]
+pub enum AckUdpError {
+  Truncated { expected: usize, actual: usize }, // Fewer bytes than the header needs
+  BadLength { payload_size: u16, actual: usize }, // Announced payload size doesn't match the bytes received
+  BadMagic, // Not an AckUdp packet
+  UnsupportedVersion(u8), // Sent by an incompatible protocol version
+  UnknownFlag(u16), // Flags this version can't handle
+  BadChecksum { expected: u32, actual: u32 }, // Corrupted on the way
+  BadSegment { seg_index: u32, total_segments: u32 }, // Segment index outside of the datagram
+  Unauthenticated, // Failed to decrypt with our pre-shared key, or not encrypted although we have one
+  Replayed, // Sealed packet that was already opened once, or one of our own sent back to us
+  Io(io::Error),
+}
+
+impl fmt::Display for AckUdpError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      AckUdpError::Truncated { expected, actual } => write!(f, "truncated packet: expected at least {expected} bytes, got {actual}"),
+      AckUdpError::BadLength { payload_size, actual } => write!(f, "bad payload length: header says {payload_size} bytes, got {actual}"),
+      AckUdpError::BadMagic => write!(f, "not an AckUdp packet"),
+      AckUdpError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {version}"),
+      AckUdpError::UnknownFlag(flags) => write!(f, "unknown packet flags {flags:#06x}"),
+      AckUdpError::BadChecksum { expected, actual } => write!(f, "bad checksum: header says {expected:#010x}, packet has {actual:#010x}"),
+      AckUdpError::BadSegment { seg_index, total_segments } => write!(f, "segment {seg_index} is out of range for a datagram of {total_segments} segments"),
+      AckUdpError::Unauthenticated => write!(f, "packet isn't sealed with our pre-shared key"),
+      AckUdpError::Replayed => write!(f, "sealed packet was replayed"),
+      AckUdpError::Io(e) => write!(f, "IO error: {e}"),
+    }
+  }
+}
+
+impl Error for AckUdpError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    match self {
+      AckUdpError::Io(e) => Some(e),
+      _ => None
+    }
+  }
+}
+
+impl From<io::Error> for AckUdpError {
+  fn from(e: io::Error) -> Self {
+    AckUdpError::Io(e)
+  }
+}
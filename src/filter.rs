@@ -0,0 +1,226 @@
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use tokio::time::Instant;
+
+use crate::AckUdpConfig;
+
+// Token bucket refilled at `packets_per_second`, holding up to `burst` packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+  pub packets_per_second: u32,
+  pub burst: u32,
+}
+
+// What happens to a packet from a given source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+  Accept,
+  Filtered, // Denied, banned or missing from a non-empty allow list
+  RateLimited,
+}
+
+struct TokenBucket {
+  tokens: f64,
+  refilled_at: Instant,
+}
+
+struct Strikes {
+  count: u32,
+  since: Instant,
+}
+
+#[derive(Default)]
+struct FilterState {
+  allowed: Vec<IpNet>,
+  denied: Vec<IpNet>,
+  bans: HashMap<IpAddr, Instant>, // Until when
+  buckets: HashMap<IpAddr, TokenBucket>,
+  strikes: HashMap<IpAddr, Strikes>, // Malformed or rate limited packets within `ban_window`
+  pruned_at: Option<Instant>,
+}
+
+// Decides, per source IP, whether a received packet is looked at at all. Checked by the listener before decoding,
+// so filtered packets cost nothing beyond the `recv_from`.
+#[derive(Default)]
+pub struct SourceFilter {
+  state: Mutex<FilterState>,
+}
+
+impl SourceFilter {
+  pub fn check(&self, config: &AckUdpConfig, ip: IpAddr) -> Verdict {
+    let mut state = self.state.lock();
+    let now = Instant::now();
+    state.prune(config, now);
+
+    if state.denied.iter().any(|net| net.contains(&ip)) {
+      return Verdict::Filtered;
+    }
+    if state.bans.get(&ip).is_some_and(|until| *until > now) {
+      return Verdict::Filtered;
+    }
+    if !state.allowed.is_empty() && !state.allowed.iter().any(|net| net.contains(&ip)) {
+      return Verdict::Filtered;
+    }
+
+    let Some(rate_limit) = config.rate_limit else {
+      return Verdict::Accept;
+    };
+    let burst = rate_limit.burst as f64;
+    let bucket = state.buckets.entry(ip).or_insert(TokenBucket { tokens: burst, refilled_at: now });
+    let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * rate_limit.packets_per_second as f64;
+    bucket.tokens = (bucket.tokens + refill).min(burst);
+    bucket.refilled_at = now;
+    if bucket.tokens < 1.0 {
+      return Verdict::RateLimited;
+    }
+    bucket.tokens -= 1.0;
+
+    Verdict::Accept
+  }
+
+  // Counts a malformed or rate limited packet against `ip`. Returns true if that got it banned.
+  pub fn strike(&self, config: &AckUdpConfig, ip: IpAddr) -> bool {
+    if config.ban_threshold == 0 {
+      return false;
+    }
+
+    let mut state = self.state.lock();
+    let now = Instant::now();
+    let strikes = state.strikes.entry(ip).or_insert(Strikes { count: 0, since: now });
+    if now.duration_since(strikes.since) > config.ban_window {
+      *strikes = Strikes { count: 0, since: now };
+    }
+    strikes.count += 1;
+    if strikes.count < config.ban_threshold {
+      return false;
+    }
+
+    state.strikes.remove(&ip);
+    state.buckets.remove(&ip);
+    state.bans.insert(ip, now + config.ban_duration);
+
+    true
+  }
+
+  pub fn allow(&self, net: IpNet) {
+    let mut state = self.state.lock();
+    if !state.allowed.contains(&net) {
+      state.allowed.push(net);
+    }
+  }
+
+  pub fn disallow(&self, net: IpNet) {
+    self.state.lock().allowed.retain(|allowed| *allowed != net);
+  }
+
+  pub fn deny(&self, net: IpNet) {
+    let mut state = self.state.lock();
+    if !state.denied.contains(&net) {
+      state.denied.push(net);
+    }
+  }
+
+  pub fn undeny(&self, net: IpNet) {
+    self.state.lock().denied.retain(|denied| *denied != net);
+  }
+
+  pub fn ban(&self, ip: IpAddr, duration: Duration) {
+    self.state.lock().bans.insert(ip, Instant::now() + duration);
+  }
+
+  pub fn unban(&self, ip: IpAddr) {
+    self.state.lock().bans.remove(&ip);
+  }
+
+  // Time left on the ban of `ip`, `None` if it isn't banned
+  pub fn ban_remaining(&self, ip: IpAddr) -> Option<Duration> {
+    let now = Instant::now();
+
+    self.state.lock().bans.get(&ip).filter(|until| **until > now).map(|until| *until - now)
+  }
+}
+
+impl FilterState {
+  // At most once a second. Spoofed sources would otherwise fill the maps, entries that don't hold anything
+  // beyond the defaults are dropped.
+  fn prune(&mut self, config: &AckUdpConfig, now: Instant) {
+    if self.pruned_at.is_some_and(|pruned_at| now.duration_since(pruned_at) < Duration::from_secs(1)) {
+      return;
+    }
+    self.pruned_at = Some(now);
+
+    self.bans.retain(|_, until| *until > now);
+    self.strikes.retain(|_, strikes| now.duration_since(strikes.since) <= config.ban_window);
+    match config.rate_limit {
+      // A bucket that refilled completely is no different from a new one
+      Some(rate_limit) => self.buckets.retain(|_, bucket| {
+        let refill = now.duration_since(bucket.refilled_at).as_secs_f64() * rate_limit.packets_per_second as f64;
+        bucket.tokens + refill < rate_limit.burst as f64
+      }),
+      None => self.buckets.clear()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ip(last: u8) -> IpAddr {
+    IpAddr::from([10, 0, 0, last])
+  }
+
+  #[test]
+  fn buckets_hold_a_burst_per_source() {
+    let config = AckUdpConfig { rate_limit: Some(RateLimit { packets_per_second: 1, burst: 3 }), ..AckUdpConfig::default() };
+    let filter = SourceFilter::default();
+    for _ in 0..3 {
+      assert_eq!(filter.check(&config, ip(1)), Verdict::Accept);
+    }
+    assert_eq!(filter.check(&config, ip(1)), Verdict::RateLimited);
+    assert_eq!(filter.check(&config, ip(2)), Verdict::Accept);
+    assert_eq!(filter.check(&AckUdpConfig::default(), ip(1)), Verdict::Accept);
+  }
+
+  #[test]
+  fn deny_wins_over_allow() {
+    let config = AckUdpConfig::default();
+    let filter = SourceFilter::default();
+    filter.allow("10.0.0.0/24".parse().unwrap());
+    assert_eq!(filter.check(&config, ip(1)), Verdict::Accept);
+    assert_eq!(filter.check(&config, IpAddr::from([10, 0, 1, 1])), Verdict::Filtered);
+
+    filter.deny("10.0.0.1/32".parse().unwrap());
+    assert_eq!(filter.check(&config, ip(1)), Verdict::Filtered);
+    assert_eq!(filter.check(&config, ip(2)), Verdict::Accept);
+
+    filter.undeny("10.0.0.1/32".parse().unwrap());
+    filter.disallow("10.0.0.0/24".parse().unwrap());
+    assert_eq!(filter.check(&config, IpAddr::from([10, 0, 1, 1])), Verdict::Accept);
+  }
+
+  #[test]
+  fn strikes_get_sources_banned() {
+    let config = AckUdpConfig { ban_threshold: 3, ..AckUdpConfig::default() };
+    let filter = SourceFilter::default();
+    assert!(!filter.strike(&config, ip(1)));
+    assert!(!filter.strike(&config, ip(1)));
+    assert!(!filter.strike(&config, ip(2)));
+    assert!(filter.strike(&config, ip(1)));
+    assert_eq!(filter.check(&config, ip(1)), Verdict::Filtered);
+    assert!(filter.ban_remaining(ip(1)).is_some_and(|remaining| remaining <= config.ban_duration));
+    assert_eq!(filter.check(&config, ip(2)), Verdict::Accept);
+
+    filter.unban(ip(1));
+    assert_eq!(filter.check(&config, ip(1)), Verdict::Accept);
+    // Expired bans are as good as none
+    filter.ban(ip(1), Duration::ZERO);
+    assert!(filter.ban_remaining(ip(1)).is_none());
+    assert_eq!(filter.check(&config, ip(1)), Verdict::Accept);
+
+    let never = AckUdpConfig::default();
+    assert!((0..100).all(|_| !filter.strike(&never, ip(3))));
+  }
+}
@@ -1,190 +1,392 @@
 use std::{
-  sync::Arc, 
-  net::SocketAddr,
+  sync::{atomic::Ordering, Arc}, 
+  net::{IpAddr, SocketAddr},
   io, 
-  collections::{HashMap, VecDeque, HashSet}
+  time::Duration
 };
-use tokio::{net::UdpSocket, sync::mpsc::{Sender, self}};
+use tokio::{net::UdpSocket, sync::{broadcast, mpsc}, task::JoinHandle, time::Instant};
 
 
-use chrono::Utc;
 use parking_lot::Mutex;
 use rand::Rng;
-use sock_send::SockSend;
-use types::{
-  AckUdpDatagram, 
-  AckUdpDatagramOutStatus, 
-  AckUdpDatagramOutStatusEnum, 
-  AckUdpPacket
-};
+use peer::AckUdpPeer;
+use session::{AckUdpSessionState, SessionStatus};
+use crypto::PacketCipher;
+use noise::NoiseSessions;
+use sock_send::{AckUdpSocket, Sealing, SockSend};
+use shared::AckUdpShared;
+use types::{AckUdpPacket, PacketFlags};
 
 mod types;
 mod sock_send;
 mod methods;
+mod config;
+mod error;
+mod peer;
+mod congestion;
+mod channel;
+mod session;
+mod crypto;
+mod noise;
+mod cookie;
+mod filter;
+mod shared;
 
-pub struct AckUdp {
-  pub sock: Arc<UdpSocket>,
-
-  pub ready_to_read_datagrams: Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>,
-  pub pending_in_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-  pub pending_out_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-
-  pub out_datagrams_status_links: Arc<Mutex<HashMap<[u8; 5], Arc<Mutex<AckUdpDatagramOutStatus>>>>>,
+pub use config::{AckUdpConfig, AckUdpBuilder};
+pub use error::AckUdpError;
+pub use peer::RttEstimate;
+pub use channel::ChannelMode;
+pub use session::AckUdpSession;
+pub use crypto::PreSharedKey;
+pub use noise::{NoiseKeypair, PublicKey};
+pub use filter::RateLimit;
+pub use ipnet::IpNet;
+pub use congestion::{CongestionControl, CongestionController, NewReno, FixedRate};
+pub use types::{DeliveryHandle, DeliveryReport, SendError, RejectReason, AckUdpDatagramOutStatusEnum, AckUdpStats, AckUdpEvent};
 
-  pub kill_listener_channel_sender: Sender<()>,
-  pub kill_income_checker_channel_sender: Sender<()>,
-  pub kill_outcome_checker_listener_channel_sender: Sender<()>,
-  pub kill_incoming_queue_channel_sender: Sender<()>,
-
-  pub incoming_queue: Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>
+pub struct AckUdp {
+  shared: Arc<AckUdpShared>,
+  tasks: Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl AckUdp {
   pub async fn new(address: SocketAddr) -> io::Result<AckUdp> {
-    let sock = Arc::new(UdpSocket::bind(address).await?);
-
-    let (listener_sender, listener_receiver) = mpsc::channel(1);
-    let (income_checker_sender, income_checker_receiver) = mpsc::channel(1);
-    let (outcome_checker_sender, outcome_checker_receiver) = mpsc::channel(1);
-    let (incoming_queue_sender, incoming_queue_receiver) = mpsc::channel(1);
-
-    let instance = AckUdp {
-      sock: sock.clone(),
-      ready_to_read_datagrams: Arc::new(Mutex::new(VecDeque::new())),
-      pending_in_datagrams: Arc::new(Mutex::new(HashMap::new())),
-      pending_out_datagrams: Arc::new(Mutex::new(HashMap::new())),
-      out_datagrams_status_links: Arc::new(Mutex::new(HashMap::new())),
-      kill_listener_channel_sender: listener_sender,
-      kill_income_checker_channel_sender: income_checker_sender,
-      kill_outcome_checker_listener_channel_sender: outcome_checker_sender,
-      kill_incoming_queue_channel_sender: incoming_queue_sender,
-      incoming_queue: Arc::new(Mutex::new(VecDeque::new()))
+    AckUdp::with_config(address, AckUdpConfig::default()).await
+  }
+
+  pub fn builder() -> AckUdpBuilder {
+    AckUdpBuilder::new()
+  }
+
+  pub async fn with_config(address: SocketAddr, config: AckUdpConfig) -> io::Result<AckUdp> {
+    config.validate()?;
+
+    let sealing = match (&config.psk, &config.noise_keypair) {
+      (Some(psk), _) => Sealing::Psk(PacketCipher::new(psk)),
+      (None, Some(keypair)) => Sealing::Noise(NoiseSessions::new(keypair.clone())),
+      (None, None) => Sealing::Plain
     };
+    let sock = AckUdpSocket::new(UdpSocket::bind(address).await?, sealing);
+    let shared = Arc::new(AckUdpShared::new(sock, config));
 
-    // Check for dropped INcome datagrams
-    tokio::spawn(AckUdp::check_dropped_income(
-      income_checker_receiver, 
-      instance.pending_in_datagrams.clone())
-    );
-
-    // Check for dropped OUTcome datagrams and resend segments for datagrams that are not already dropped
-    tokio::spawn(AckUdp::check_dropped_outcome(
-      outcome_checker_receiver, 
-      instance.pending_out_datagrams.clone(), 
-      instance.sock.clone(), 
-      instance.out_datagrams_status_links.clone()
-    ));
-
-    // Listen for incoming packets on socket
-    tokio::spawn(AckUdp::listen_packets(listener_receiver, instance.sock.clone(), instance.incoming_queue.clone()));
-
-    // Proccess packet 
-    tokio::spawn(AckUdp::process_packets(
-      incoming_queue_receiver, 
-      instance.incoming_queue.clone(), 
-      instance.sock.clone(), 
-      instance.ready_to_read_datagrams.clone(), 
-      instance.pending_in_datagrams.clone(), 
-      instance.pending_out_datagrams.clone(), 
-      instance.out_datagrams_status_links.clone()
-    ));
-
-    Ok(instance)
-  }
-
-  pub fn recv(&mut self) -> Option<(SocketAddr, Vec<u8>)> {
-    self.ready_to_read_datagrams.lock().pop_front()
-  }
-
-  pub fn send(&mut self, buf: &[u8], address: SocketAddr ) -> io::Result<Arc<Mutex<AckUdpDatagramOutStatus>>> {
-    let datagram_id = rand::thread_rng().gen::<[u8; 5]>();
-    let status = Arc::new(Mutex::new(AckUdpDatagramOutStatus(AckUdpDatagramOutStatusEnum::Pending)));
-    if buf.len() > 400 {
-      let segments_count = {
-        let a = buf.len() as f64 / 400 as f64;
-        a.ceil() as u32
-      };
-
-      let segments = Arc::new(Mutex::new(HashMap::new()));
-
-      let datagram = AckUdpDatagram {
-        id: datagram_id,
-        address,
-        segments_count: segments_count,
-        segments: segments.clone(),
-        segments_got: Arc::new(Mutex::new(vec![])),
-        segments_acks: Arc::new(Mutex::new(HashSet::new())),
-        last_active: Utc::now(),
-        checks_failure_count: 0
-      };
-
-      self.pending_out_datagrams.lock().insert(datagram_id, datagram.clone());
-      self.out_datagrams_status_links.lock().insert(datagram_id, status.clone());
-
-      let buffer_length = buf.len();
-      for index in 0..segments_count {
-        let start = 400 * index as usize;
-        let end = {
-          let v = start + 400;
-          if v > buffer_length {
-            buffer_length
-          }
-          else {
-            v
-          }
-        };
-        let payload = &buf[start..end];
-        let packet = AckUdpPacket { 
-          datagram_id, 
-          seg_index: index, 
-          total_segments: segments_count, 
-          ack: 0, 
-          payload_size: payload.len() as u16, 
-          payload: payload.to_vec()
+    // Bounded, so a flood of packets slows the listener down instead of growing memory
+    let (incoming_sender, incoming_receiver) = mpsc::channel(shared.config.incoming_queue_capacity);
+
+    let tasks = vec![
+      // Check for dropped INcome datagrams
+      tokio::spawn(AckUdp::check_dropped_income(shared.clone())),
+      // Check for dropped OUTcome datagrams and resend segments for datagrams that are not already dropped, ping quiet peers
+      tokio::spawn(AckUdp::check_dropped_outcome(shared.clone())),
+      // Listen for incoming packets on socket
+      tokio::spawn(AckUdp::listen_packets(shared.clone(), incoming_sender)),
+      // Proccess packet 
+      tokio::spawn(AckUdp::process_packets(shared.clone(), incoming_receiver)),
+    ];
+
+    Ok(AckUdp { shared, tasks: Mutex::new(tasks) })
+  }
+
+  pub fn local_addr(&self) -> io::Result<SocketAddr> {
+    self.shared.sock.local_addr()
+  }
+
+  pub fn config(&self) -> &AckUdpConfig {
+    &self.shared.config
+  }
+
+  // Counters of what was discarded or refused and why
+  pub fn stats(&self) -> &AckUdpStats {
+    &self.shared.stats
+  }
+
+  // INcome bytes being reassembled or waiting to be read, what counts against `receive_window`
+  pub fn buffered_bytes(&self) -> usize {
+    self.shared.buffered_in_bytes.load(Ordering::Relaxed)
+  }
+
+  // Waits until a fully reassembled datagram is available. Returns `None` once the socket is shut down
+  // and everything that was already received has been read.
+  pub async fn recv(&self) -> Option<(SocketAddr, Vec<u8>)> {
+    self.recv_from_channel(None).await
+  }
+
+  // Same as `recv`, but only for datagrams sent on `channel`
+  pub async fn recv_on(&self, channel: u8) -> Option<(SocketAddr, Vec<u8>)> {
+    self.recv_from_channel(Some(channel)).await
+  }
+
+  // Same as `recv`, but gives up with `None` once `timeout` elapses
+  pub async fn recv_timeout(&self, timeout: Duration) -> Option<(SocketAddr, Vec<u8>)> {
+    tokio::time::timeout(timeout, self.recv()).await.ok().flatten()
+  }
+
+  // Same as `recv`, along with the static key the sender proved it holds in the Noise handshake.
+  // The key is `None` unless `noise_keypair` is set.
+  pub async fn recv_authenticated(&self) -> Option<(SocketAddr, Option<PublicKey>, Vec<u8>)> {
+    AckUdp::wait_ready(
+      &self.shared,
+      &self.shared.cancellation_token,
+      None,
+      |address| self.is_readable(address)
+    ).await
+  }
+
+  async fn recv_from_channel(&self, channel: Option<u8>) -> Option<(SocketAddr, Vec<u8>)> {
+    AckUdp::wait_ready(
+      &self.shared,
+      &self.shared.cancellation_token,
+      channel,
+      |address| self.is_readable(address)
+    ).await.map(|(address, _, buf)| (address, buf))
+  }
+
+  // Current RTT estimate for `address`, `None` until one of its ACKs has been sampled
+  pub fn peer_rtt(&self, address: SocketAddr) -> Option<RttEstimate> {
+    self.shared.peers.lock().get(&address).and_then(|peer| peer.rtt.estimate())
+  }
+
+  // Ordered delivery to `address`: every datagram sent from now on carries a sequence number and the peer
+  // doesn't hand it to `recv` before all earlier ones are read or dropped by us
+  pub fn set_ordered_delivery(&self, address: SocketAddr, ordered: bool) {
+    self.shared.peers.lock().entry(address).or_insert_with(|| AckUdpPeer::new(&self.shared.config)).ordered = ordered;
+  }
+
+  // Our static key, what peers get from `recv_authenticated`. `None` unless `noise_keypair` is set.
+  pub fn local_public_key(&self) -> Option<PublicKey> {
+    self.shared.config.noise_keypair.as_ref().map(|keypair| keypair.public)
+  }
+
+  // Static key of `address`, `None` until the Noise handshake with it is done
+  pub fn peer_public_key(&self, address: SocketAddr) -> Option<PublicKey> {
+    self.shared.sock.sealing.remote_key(address)
+  }
+
+  // Events from now on. A receiver that falls more than 64 events behind skips the oldest ones.
+  pub fn subscribe_events(&self) -> broadcast::Receiver<AckUdpEvent> {
+    self.shared.events.subscribe()
+  }
+
+  // Once anything is allowed, packets from IPs outside every allowed range are discarded
+  pub fn allow(&self, net: IpNet) {
+    self.shared.filter.allow(net);
+  }
+
+  pub fn disallow(&self, net: IpNet) {
+    self.shared.filter.disallow(net);
+  }
+
+  // Packets from `net` are discarded, even if it's allowed as well
+  pub fn deny(&self, net: IpNet) {
+    self.shared.filter.deny(net);
+  }
+
+  pub fn undeny(&self, net: IpNet) {
+    self.shared.filter.undeny(net);
+  }
+
+  // Discards everything from `ip` for `duration`, replacing any ban it already has
+  pub fn ban(&self, ip: IpAddr, duration: Duration) {
+    self.shared.filter.ban(ip, duration);
+  }
+
+  pub fn unban(&self, ip: IpAddr) {
+    self.shared.filter.unban(ip);
+  }
+
+  // Time left on the ban of `ip`, banned by hand or for going over `ban_threshold`
+  pub fn ban_remaining(&self, ip: IpAddr) -> Option<Duration> {
+    self.shared.filter.ban_remaining(ip)
+  }
+
+  // Current congestion window of `address` in bytes
+  pub fn peer_congestion_window(&self, address: SocketAddr) -> Option<usize> {
+    self.shared.peers.lock().get(&address).map(|peer| peer.congestion.window())
+  }
+
+  // Returns `None` right away if nothing is ready to be read
+  pub fn try_recv(&self) -> Option<(SocketAddr, Vec<u8>)> {
+    self.try_recv_from_channel(None)
+  }
+
+  pub fn try_recv_on(&self, channel: u8) -> Option<(SocketAddr, Vec<u8>)> {
+    self.try_recv_from_channel(Some(channel))
+  }
+
+  fn try_recv_from_channel(&self, channel: Option<u8>) -> Option<(SocketAddr, Vec<u8>)> {
+    AckUdp::take_ready(
+      &self.shared,
+      channel,
+      |address| self.is_readable(address)
+    ).map(|(address, _, buf)| (address, buf))
+  }
+
+  // Datagrams of peers we have a session with are read from the session
+  fn is_readable(&self, address: SocketAddr) -> bool {
+    !self.shared.sessions.lock().contains_key(&address)
+  }
+
+  // Reliable send on channel 0
+  pub fn send(&mut self, buf: &[u8], address: SocketAddr ) -> io::Result<DeliveryHandle> {
+    self.send_on(0, buf, address).map(|delivery| delivery.expect("channel 0 is reliable"))
+  }
+
+  // Sends with the guarantees `channel` is configured with. Unreliable channels give no `DeliveryHandle`,
+  // there is nothing to wait for once the segments are handed to the socket.
+  pub fn send_on(&mut self, channel: u8, buf: &[u8], address: SocketAddr) -> io::Result<Option<DeliveryHandle>> {
+    if self.shared.cancellation_token.is_cancelled() {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "socket is shut down"));
+    }
+
+    Ok(AckUdp::send_datagram(&self.shared, channel, buf, address))
+  }
+
+  // Fire and forget on channel 0: the receiver doesn't ACK it and nothing is kept around to resend it.
+  // Use an unreliable sequenced channel if older datagrams should be discarded once a newer one is read.
+  pub fn send_unreliable(&self, buf: &[u8], address: SocketAddr) -> io::Result<()> {
+    if self.shared.cancellation_token.is_cancelled() {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "socket is shut down"));
+    }
+
+    AckUdp::send_unreliable_datagram(&self.shared, buf, address);
+
+    Ok(())
+  }
+
+  // Opens a session with `address`, which has to have `accept_sessions` set. Fails with `ConnectionRefused`
+  // if the peer refuses it and `TimedOut` if it doesn't answer within `handshake_timeout`.
+  // A session we already have with `address` is closed.
+  pub async fn connect(&self, address: SocketAddr) -> io::Result<AckUdpSession> {
+    if self.shared.cancellation_token.is_cancelled() {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "socket is shut down"));
+    }
+
+    let session_id = rand::thread_rng().gen::<[u8; 5]>();
+    let state = AckUdpSessionState::new(session_id, SessionStatus::Connecting, self.shared.config.segment_size, self.shared.cancellation_token.child_token());
+    let (changed, closed) = (state.changed.clone(), state.closed.clone());
+    if let Some(previous) = self.shared.sessions.lock().insert(address, state) {
+      previous.close();
+    }
+
+    let deadline = Instant::now() + self.shared.config.handshake_timeout;
+    let mut attempt = 0;
+    loop {
+      let notified = changed.notified();
+      let status = self.shared.sessions.lock().get(&address)
+        .filter(|state| state.id == session_id)
+        .map(|state| (state.status, state.segment_size));
+      match status {
+        Some((SessionStatus::Established, segment_size)) => {
+          return Ok(AckUdpSession::new(&self.shared, address, session_id, segment_size, closed));
+        }
+        Some(_) if !closed.is_cancelled() => {}
+        _ => {
+          return Err(io::Error::new(io::ErrorKind::ConnectionRefused, "peer refused the session"));
+        }
+      }
+      if Instant::now() >= deadline {
+        let mut sessions = self.shared.sessions.lock();
+        if sessions.get(&address).is_some_and(|state| state.id == session_id) {
+          sessions.remove(&address);
+        }
+
+        return Err(io::Error::new(io::ErrorKind::TimedOut, "peer didn't answer the session handshake"));
+      }
+
+      self.shared.sock.sock_send(AckUdpPacket::new_connect(session_id, self.shared.config.segment_size, PacketFlags::CONNECT), address);
+      let rto = self.shared.peers.lock().entry(address).or_insert_with(|| AckUdpPeer::new(&self.shared.config)).rtt.rto(attempt);
+      attempt = attempt.saturating_add(1);
+
+      tokio::select! {
+        _ = notified => {},
+        _ = closed.cancelled() => {},
+        _ = tokio::time::sleep_until((Instant::now() + rto).min(deadline)) => {}
+      }
+    }
+  }
+
+  // Waits for a peer to `connect`. Returns `None` right away unless `accept_sessions` is set,
+  // and once the socket is shut down.
+  pub async fn accept(&self) -> Option<AckUdpSession> {
+    if !self.shared.config.accept_sessions {
+      return None;
+    }
+
+    loop {
+      let notified = self.shared.accept_notify.notified();
+      loop {
+        let Some((address, session_id)) = self.shared.accept_queue.lock().pop_front() else {
+          break;
         };
-        segments.lock().insert(index, packet.clone());
-        
-        let packet_bytes: Vec<u8> = packet.into();
-        self.sock.sock_send(packet_bytes, address);
+        // Closed or replaced by a newer CONNECT before anyone accepted it
+        let state = self.shared.sessions.lock().get(&address)
+          .filter(|state| state.id == session_id && !state.closed.is_cancelled())
+          .map(|state| (state.segment_size, state.closed.clone()));
+        if let Some((segment_size, closed)) = state {
+          return Some(AckUdpSession::new(&self.shared, address, session_id, segment_size, closed));
+        }
       }
+      if self.shared.cancellation_token.is_cancelled() {
+        return None;
+      }
+
+      tokio::select! {
+        _ = notified => {},
+        _ = self.shared.cancellation_token.cancelled() => {}
+      }
+    }
+  }
 
-      let mut sent_datagram = self.pending_out_datagrams.lock().get(&datagram_id).unwrap().clone();
-      sent_datagram.last_active = Utc::now();
-      self.pending_out_datagrams.lock().insert(datagram_id, sent_datagram);
+  // Stops all background tasks. With `drain_timeout` set, first waits (up to that long) for pending
+  // OUTcome datagrams to be ACKed or dropped. Whatever is still pending afterwards fails with `SendError::Shutdown`.
+  pub async fn shutdown(&self, drain_timeout: Option<Duration>) {
+    if let Some(drain_timeout) = drain_timeout {
+      let pending: Vec<DeliveryHandle> = self.shared.out_datagrams_status_links.lock()
+        .values()
+        .map(|status| DeliveryHandle::new(status.clone()))
+        .collect();
 
-      return Ok(status.clone());
+      let _ = tokio::time::timeout(drain_timeout, async {
+        for delivery in pending {
+          let _ = delivery.await;
+        }
+      }).await;
     }
-    else {
-      let packet = AckUdpPacket { 
-        datagram_id,
-        seg_index: 0,
-        total_segments: 1,
-        ack: 0,
-        payload_size: buf.len() as u16, 
-        payload: buf.to_vec()
-      };
-
-      let segments= Arc::new(Mutex::new(HashMap::new()));
-      segments.lock().insert(0, packet.clone());
-
-      let datagram = AckUdpDatagram {
-        id: datagram_id,
-        address,
-        segments_count: 1,
-        segments,
-        segments_got: Arc::new(Mutex::new(vec![])),
-        segments_acks: Arc::new(Mutex::new(HashSet::new())),
-        last_active: Utc::now(),
-        checks_failure_count: 0
-      };
-
-      self.pending_out_datagrams.lock().insert(datagram_id, datagram);
-      self.out_datagrams_status_links.lock().insert(datagram_id, status.clone());
-      
-      let packet_bytes: Vec<u8> = packet.into();
-      self.sock.sock_send(packet_bytes, address);
-
-      Ok(status.clone())
+
+    self.shared.cancellation_token.cancel();
+
+    let tasks: Vec<JoinHandle<()>> = self.tasks.lock().drain(..).collect();
+    for task in tasks {
+      let _ = task.await;
+    }
+
+    // Peers would otherwise hold later ordered datagrams back until `incoming_expiry`
+    for datagram in self.shared.pending_out_datagrams.lock().drain().map(|(_, datagram)| datagram) {
+      if datagram.sequence != 0 {
+        self.shared.sock.sock_send(AckUdpPacket::new_dropped(datagram.id, datagram.channel, datagram.sequence, datagram.epoch), datagram.address);
+      }
+    }
+    self.shared.pending_in_datagrams.lock().clear();
+    for (address, state) in self.shared.sessions.lock().drain() {
+      self.shared.sock.sock_send(AckUdpPacket::new_close(state.id, PacketFlags::CLOSE), address);
+      state.close();
+    }
+    for (_, status) in self.shared.out_datagrams_status_links.lock().drain() {
+      status.lock().fail(SendError::Shutdown);
     }
   }
-}
\ No newline at end of file
+}
+
+impl Drop for AckUdp {
+  fn drop(&mut self) {
+    self.shared.cancellation_token.cancel();
+    for task in self.tasks.lock().drain(..) {
+      task.abort();
+    }
+
+    // Nothing is left to resend them, whoever awaits them would wait forever
+    self.shared.pending_out_datagrams.lock().clear();
+    for (_, status) in self.shared.out_datagrams_status_links.lock().drain() {
+      status.lock().fail(SendError::Shutdown);
+    }
+  }
+}
@@ -0,0 +1,58 @@
+use std::{sync::atomic::Ordering, net::SocketAddr};
+
+use crate::{
+  AckUdp,
+  types::{AckUdpPacket, PacketFlags, RejectReason, MIN_SEGMENT_SIZE},
+  sock_send::SockSend,
+  shared::AckUdpShared
+};
+
+impl AckUdp {
+  // Limits on reassembly state, checked before the first segment of a new datagram is kept.
+  // Once `max_pending_in` is reached the least recently active datagram is evicted to make room.
+  pub(crate) fn admit_datagram(shared: &AckUdpShared, src_addr: SocketAddr, packet: &AckUdpPacket) -> Result<(), RejectReason> {
+    let AckUdpShared { config, sock: socket, stats, pending_in_datagrams, buffered_in_bytes, .. } = shared;
+    // Every segment but the last one is as big as the sender's segment size, the last one can be tiny
+    let total_segments = packet.total_segments as usize;
+    if packet.seg_index + 1 < packet.total_segments && packet.payload.len() < MIN_SEGMENT_SIZE {
+      return Err(RejectReason::Malformed);
+    }
+    if total_segments > config.max_datagram_size / MIN_SEGMENT_SIZE {
+      return Err(RejectReason::TooBig);
+    }
+    let size = if packet.seg_index + 1 < packet.total_segments {
+      total_segments.saturating_mul(packet.payload.len())
+    }
+    else {
+      (total_segments - 1).saturating_mul(MIN_SEGMENT_SIZE) + packet.payload.len()
+    };
+    if size > config.max_datagram_size {
+      return Err(RejectReason::TooBig);
+    }
+
+    let mut pending_in_datagrams = pending_in_datagrams.lock();
+    if pending_in_datagrams.values().filter(|datagram| datagram.address == src_addr).count() >= config.max_pending_in_per_peer {
+      return Err(RejectReason::TooManyPending);
+    }
+    if pending_in_datagrams.len() < config.max_pending_in {
+      return Ok(());
+    }
+
+    let oldest = pending_in_datagrams.iter()
+      .min_by_key(|(_, datagram)| datagram.last_active)
+      .map(|(id, _)| *id);
+    if let Some(evicted) = oldest.and_then(|id| pending_in_datagrams.remove(&id)) {
+      drop(pending_in_datagrams);
+
+      buffered_in_bytes.fetch_sub(evicted.buffered_bytes(), Ordering::Relaxed);
+      stats.evicted_datagrams.fetch_add(1, Ordering::Relaxed);
+      // Unreliable senders don't keep anything around to fail
+      let reliable = evicted.segments.lock().values().next().is_some_and(|segment| !segment.flags.contains(PacketFlags::UNRELIABLE));
+      if reliable {
+        socket.sock_send(AckUdpPacket::new_rejected(evicted.id, RejectReason::Evicted), evicted.address);
+      }
+    }
+
+    Ok(())
+  }
+}
@@ -1,36 +1,48 @@
-use std::{io, sync::Arc, collections::HashMap};
+use std::sync::{atomic::Ordering, Arc};
 
-use chrono::Utc;
-use parking_lot::Mutex;
-use tokio::sync::mpsc::Receiver;
-
-use crate::{types::AckUdpDatagram, AckUdp};
+use crate::{
+  methods::process_packets::push_ready, 
+  shared::AckUdpShared, 
+  AckUdp
+};
 
 impl AckUdp {
-  pub async fn check_dropped_income(
-    mut income_checker_receiver: Receiver<()>, 
-    pending_in_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>
-  ) -> io::Result<()> {
+  pub(crate) async fn check_dropped_income(shared: Arc<AckUdpShared>) {
+    let AckUdpShared { cancellation_token, config, sock: socket, pending_in_datagrams, delivered_datagrams, ordered_inboxes, buffered_in_bytes, .. } = &*shared;
     loop {
-      if let Ok(()) = income_checker_receiver.try_recv() {
-        break;
-      }
-  
-      let datagrams = pending_in_datagrams.lock().clone();
-      for (id, datagram) in datagrams.into_iter() {
-        
-        let start_time = datagram.last_active.time();
-        let end_time = Utc::now().time();
-        let diff = end_time - start_time;
-        
-        // println!("IN total_segments: {}, got: {}, diff: {}", datagram.segments_count, datagram.segments.lock().len(), diff.num_seconds());
-        if diff.num_seconds() >= 30 {
-          pending_in_datagrams.lock().remove(&id);
+      // Whatever removes a datagram from the map gives its bytes back, it can't be removed twice
+      let mut expired = 0;
+      pending_in_datagrams.lock().retain(|_, datagram| {
+        let is_expired = datagram.last_active.elapsed() >= config.incoming_expiry;
+        if is_expired {
+          expired += datagram.buffered_bytes();
+        }
+
+        !is_expired
+      });
+      buffered_in_bytes.fetch_sub(expired, Ordering::Relaxed);
+
+      delivered_datagrams.lock().retain(|_, window| {
+        window.expire(config.dedup_window_ttl);
+        !window.is_empty()
+      });
+
+      // Ordered datagrams waited long enough for earlier ones that are never coming
+      {
+        let mut ordered_inboxes = ordered_inboxes.lock();
+        for ((address, channel), inbox) in ordered_inboxes.iter_mut() {
+          let released = inbox.expire(config.incoming_expiry);
+          if !released.is_empty() {
+            push_ready(&shared, *address, *channel, socket.sealing.remote_key(*address), released);
+          }
         }
+        ordered_inboxes.retain(|_, inbox| !inbox.is_idle(config.incoming_expiry));
+      }
+
+      tokio::select! {
+        _ = cancellation_token.cancelled() => break,
+        _ = tokio::time::sleep(tokio::time::Duration::from_millis(100)) => {}
       }
-      tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
     }
-  
-    Ok(())
   }
 }
\ No newline at end of file
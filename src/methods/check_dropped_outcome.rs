@@ -1,77 +1,144 @@
-use std::{sync::Arc, collections::HashMap};
+use std::{sync::Arc, collections::HashSet, net::SocketAddr};
 
-use chrono::Utc;
-use parking_lot::Mutex;
-use tokio::{net::UdpSocket, sync::mpsc::Receiver};
+use tokio::time::Instant;
 
-use crate::{types::{AckUdpDatagram, AckUdpDatagramOutStatusEnum, AckUdpDatagramOutStatus}, sock_send::SockSend, AckUdp};
+use crate::{
+  types::{SendError, AckUdpEvent, AckUdpPacket, PacketFlags}, 
+  sock_send::{Sealing, SockSend}, 
+  peer::AckUdpPeer, 
+  session::SessionStatus,
+  shared::AckUdpShared,
+  AckUdp
+};
 
 impl AckUdp {
-  pub async fn check_dropped_outcome(
-    mut outcome_checker_receiver: Receiver<()>, 
-    pending_out_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-    socket: Arc<UdpSocket>,
-    out_datagrams_status_links: Arc<Mutex<HashMap<[u8; 5], Arc<Mutex<AckUdpDatagramOutStatus>>>>>
-  ) {
+  pub(crate) async fn check_dropped_outcome(shared: Arc<AckUdpShared>) {
+    let AckUdpShared { cancellation_token, config, pending_out_datagrams, sock: socket, out_datagrams_status_links, peers, sessions, .. } = &*shared;
     loop {
-      if let Ok(()) = outcome_checker_receiver.try_recv() {
-        break;
-      }
-      
       let datagrams = pending_out_datagrams.lock().clone();
+      let mut addresses = HashSet::new();
       for (id, datagram) in datagrams.into_iter() {
-        let start_time = datagram.last_active.time();
-        let end_time = Utc::now().time();
-        let diff = end_time - start_time;
-        
-        println!(
-          "failures: {}, total_segments: {}, acks: {}, diff: {}", 
-          datagram.checks_failure_count, datagram.segments_count, datagram.segments_acks.lock().len(), diff.num_milliseconds());
-        if diff.num_milliseconds() >= 500 {
-          if datagram.checks_failure_count < 200 {
-            let mut cdatagram = datagram.clone();
-            let cc2_pending_out_datagrams = pending_out_datagrams.clone();
-  
-            cdatagram.checks_failure_count += 1;
-            cc2_pending_out_datagrams.lock().insert(id, cdatagram.clone());
-  
-            tokio::spawn(resend_packets(pending_out_datagrams.clone(), socket.clone(), id));
+        addresses.insert(datagram.address);
+
+        // Segments still waiting for the congestion window can't time out
+        if !datagram.has_in_flight() {
+          continue;
+        }
+
+        let mut peers = peers.lock();
+        let peer = peers.entry(datagram.address).or_insert_with(|| AckUdpPeer::new(config));
+        if datagram.last_active.elapsed() < peer.rtt.rto(datagram.checks_failure_count) {
+          continue;
+        }
+
+        if datagram.checks_failure_count < config.max_resend_attempts {
+          // Everything in flight is considered lost and goes back to pending, `transmit_pending` resends it
+          peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(datagram.mark_lost());
+          // A segment probing a closed receive window is expected to go unACKed, that's no sign of congestion
+          if peer.receive_window >= config.segment_size {
+            peer.congestion.on_timeout();
           }
-          else {
-            pending_out_datagrams.lock().remove(&id);
-            if out_datagrams_status_links.lock().contains_key(&id) {
-              out_datagrams_status_links.lock().get(&id).unwrap().lock().0 = AckUdpDatagramOutStatusEnum::Dropped;
-            }
+          drop(peers);
+
+          if let Some(datagram) = pending_out_datagrams.lock().get_mut(&id) {
+            datagram.checks_failure_count += 1;
+            datagram.last_active = Instant::now();
           }
         }
+        else {
+          peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(datagram.bytes_in_flight());
+          drop(peers);
+
+          pending_out_datagrams.lock().remove(&id);
+          // Best effort, if this gets lost the receiver stops waiting after `incoming_expiry`
+          if datagram.sequence != 0 {
+            socket.sock_send(AckUdpPacket::new_dropped(id, datagram.channel, datagram.sequence, datagram.epoch), datagram.address);
+          }
+          if let Some(status) = out_datagrams_status_links.lock().remove(&id) {
+            status.lock().fail(SendError::Dropped { attempts: datagram.checks_failure_count });
+          }
+        }
+      }
+
+      for &address in &addresses {
+        AckUdp::transmit_pending(&shared, address);
+      }
+
+      // Noise handshake messages the peer hasn't answered yet
+      if let Sealing::Noise(noise) = &socket.sealing {
+        for (address, message) in noise.retransmits(config) {
+          socket.sock_send_plain(message, address);
+        }
+      }
+
+      // Peers we expect answers from are pinged once quiet and given up on once silent for too long
+      let (established, with_session): (HashSet<SocketAddr>, HashSet<SocketAddr>) = {
+        let sessions = sessions.lock();
+        let established = sessions.iter()
+          .filter(|(_, state)| state.status == SessionStatus::Established)
+          .map(|(address, _)| *address)
+          .collect();
+
+        (established, sessions.keys().copied().collect())
+      };
+      // Everyone who ever sent us a packet would be kept forever otherwise
+      peers.lock().retain(|address, peer| addresses.contains(address) || with_session.contains(address) || !peer.is_idle(config));
+      let mut dead = vec![];
+      for (address, peer) in peers.lock().iter_mut() {
+        if !addresses.contains(address) && !established.contains(address) {
+          peer.expecting_since = None;
+          continue;
+        }
+
+        peer.expecting_since.get_or_insert_with(Instant::now);
+        if peer.silence() >= config.peer_timeout {
+          peer.expecting_since = None;
+          dead.push(*address);
+        }
+        else if peer.silence() >= config.keepalive_interval && peer.last_ping.elapsed() >= config.keepalive_interval {
+          socket.sock_send(AckUdpPacket::new_ping(PacketFlags::PING), *address);
+          peer.last_ping = Instant::now();
+        }
+      }
+      for address in dead {
+        peer_dead(&shared, address);
+      }
+
+      tokio::select! {
+        _ = cancellation_token.cancelled() => break,
+        _ = tokio::time::sleep(config.timer_granularity) => {}
       }
-      tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
     }
   }
 }
 
-async fn resend_packets(
-  pending_out_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-  socket: Arc<UdpSocket>,
-  id: [u8; 5]
-) {
-  let datagram = match pending_out_datagrams.lock().get(&id) {
-    Some(v) => v.clone(),
-    None => return
+fn peer_dead(shared: &AckUdpShared, address: SocketAddr) {
+  let AckUdpShared { pending_out_datagrams, out_datagrams_status_links, peers, sessions, events, .. } = shared;
+  let ids: Vec<[u8; 5]> = {
+    let mut pending_out_datagrams = pending_out_datagrams.lock();
+    let ids: Vec<[u8; 5]> = pending_out_datagrams.values()
+      .filter(|datagram| datagram.address == address)
+      .map(|datagram| datagram.id)
+      .collect();
+    for id in &ids {
+      pending_out_datagrams.remove(id);
+    }
+
+    ids
   };
-  let non_ack_segments = datagram.get_non_ack_segments();
-  println!("resending packets: {}, total: {}", non_ack_segments.len(), datagram.segments_count);
-  
-  for packet in non_ack_segments {
-    let packet_bytes: Vec<u8> = packet.into();
-    socket.sock_send(packet_bytes, datagram.address);
+  if let Some(peer) = peers.lock().get_mut(&address) {
+    peer.bytes_in_flight = 0;
+  }
+  for id in ids {
+    if let Some(status) = out_datagrams_status_links.lock().remove(&id) {
+      status.lock().fail(SendError::PeerDead);
+    }
   }
 
-  let mut datagram = match pending_out_datagrams.lock().get(&id) {
-    Some(v) => v.clone(),
-    None => return
-  };
-  
-  datagram.last_active = Utc::now();
-  pending_out_datagrams.lock().insert(id, datagram);
-}
\ No newline at end of file
+  if let Some(state) = sessions.lock().remove(&address) {
+    state.close();
+  }
+
+  // Nobody may be listening
+  let _ = events.send(AckUdpEvent::PeerDead(address));
+}
@@ -1,31 +1,93 @@
-use std::{sync::Arc, io, collections::VecDeque, net::SocketAddr};
+use std::{net::IpAddr, sync::{atomic::Ordering, Arc}};
 
-use parking_lot::Mutex;
-use tokio::{net::UdpSocket, sync::mpsc::Receiver};
+use tokio::sync::{broadcast, mpsc::{error::TrySendError, Sender}};
 
-use crate::AckUdp;
+use crate::{
+  types::{AckUdpEvent, AckUdpPacket, IncomingPacket}, 
+  filter::{SourceFilter, Verdict}, 
+  shared::AckUdpShared, 
+  AckUdp, 
+  AckUdpConfig, 
+  AckUdpError
+};
 
 impl AckUdp {
-  pub async fn listen_packets(
-    mut listener_receiver: Receiver<()>, 
-    socket: Arc<UdpSocket>, 
-    incoming_queue: Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>
-  ) {
+  pub(crate) async fn listen_packets(shared: Arc<AckUdpShared>, incoming_sender: Sender<IncomingPacket>) {
+    let AckUdpShared { cancellation_token, config, stats, sock: socket, filter, events, .. } = &*shared;
+    let mut buf = vec![0; config.recv_buffer_size];
     loop {
-      if let Ok(()) = listener_receiver.try_recv() {
-        break;
-      }
-  
-      let mut buf = [0; 416];
-      let received = match socket.recv_from(&mut buf).await {
-        Ok(v) => Some(v),
-        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => None,
-        Err(e) => panic!("encountered IO error: {e}"),
+      let result = tokio::select! {
+        _ = cancellation_token.cancelled() => break,
+        result = socket.recv_from(&mut buf) => result
       };
-      
-      if received.is_some() {
-        incoming_queue.lock().push_back((received.unwrap().1, buf.to_vec()));
+
+      // Filtered before any decoding, so a flood costs as little as possible
+      if let Ok((_, src_addr)) = result {
+        match filter.check(config, src_addr.ip()) {
+          Verdict::Accept => {}
+          Verdict::Filtered => {
+            stats.filtered_packets.fetch_add(1, Ordering::Relaxed);
+            continue;
+          }
+          Verdict::RateLimited => {
+            stats.rate_limited_packets.fetch_add(1, Ordering::Relaxed);
+            strike(config, filter, events, Some(src_addr.ip()));
+            continue;
+          }
+        }
       }
+
+      let src_ip = result.as_ref().ok().map(|(_, src_addr)| src_addr.ip());
+      let received = result
+        .map_err(AckUdpError::from)
+        .and_then(|(length, src_addr)| {
+          let mut packet = AckUdpPacket::try_from(&buf[..length])?;
+          socket.sealing.open(src_addr, &mut packet, &buf[..length])?;
+
+          Ok((src_addr, packet))
+        });
+
+      match received {
+        // Dropped while the processor is overloaded, so a flood neither grows memory nor stops us reading the socket.
+        // Reliable senders resend what got lost.
+        Ok(incoming) => match incoming_sender.try_send(incoming) {
+          Ok(()) => {}
+          Err(TrySendError::Full(_)) => {
+            stats.overloaded_packets.fetch_add(1, Ordering::Relaxed);
+          }
+          Err(TrySendError::Closed(_)) => break
+        },
+        // Receive errors on UDP are transient (e.g. ICMP port unreachable from an earlier send), keep listening
+        Err(AckUdpError::Io(_)) => {
+          stats.io_errors.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(AckUdpError::Unauthenticated) => {
+          stats.unauthenticated_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(AckUdpError::Replayed) => {
+          stats.replayed_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(AckUdpError::BadChecksum { .. }) => {
+          stats.corrupted_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(AckUdpError::BadMagic | AckUdpError::UnsupportedVersion(_) | AckUdpError::UnknownFlag(_)) => {
+          stats.incompatible_packets.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(_) => {
+          stats.malformed_packets.fetch_add(1, Ordering::Relaxed);
+          strike(config, filter, events, src_ip);
+        }
+      }
+    }
+  }
+}
+
+// Only malformed and rate limited packets count. Corrupted, foreign or unauthenticated ones may come from
+// a bad link, another version or a peer that restarted.
+fn strike(config: &AckUdpConfig, filter: &SourceFilter, events: &broadcast::Sender<AckUdpEvent>, ip: Option<IpAddr>) {
+  if let Some(ip) = ip {
+    if filter.strike(config, ip) {
+      let _ = events.send(AckUdpEvent::PeerBanned(ip));
     }
   }
 }
\ No newline at end of file
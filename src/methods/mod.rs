@@ -1,4 +1,12 @@
+mod admit_datagram;
 mod check_dropped_income;
 mod check_dropped_outcome;
 mod listen_packets;
-mod process_packets;
\ No newline at end of file
+mod process_cookie_packet;
+mod process_handshake_packet;
+mod process_packets;
+mod process_rejected_packet;
+mod process_session_packet;
+mod read_ready;
+mod send_datagram;
+mod transmit_pending;
@@ -0,0 +1,61 @@
+use std::net::SocketAddr;
+
+use crate::{
+  AckUdp,
+  types::{AckUdpPacket, PacketFlags},
+  sock_send::SockSend,
+  peer::AckUdpPeer,
+  session::SessionStatus,
+  shared::AckUdpShared
+};
+
+impl AckUdp {
+  // Cookie challenges from peers with `cookie_challenge` set, and our own cookies echoed back
+  pub(crate) fn process_cookie_packet(shared: &AckUdpShared, src_addr: SocketAddr, packet: &AckUdpPacket) {
+    let AckUdpShared { config, sock: socket, cookies, pending_out_datagrams, peers, sessions, .. } = shared;
+    if packet.flags.contains(PacketFlags::ACK) {
+      if cookies.verify(src_addr, &packet.payload) {
+        peers.lock().entry(src_addr).or_insert_with(|| AckUdpPeer::new(config)).validated = true;
+      }
+
+      return;
+    }
+
+    // Only challenges about our own datagrams, recent unreliable ones included, and our own CONNECTs are answered,
+    // so we can't be made to echo to anyone else
+    let is_ours = pending_out_datagrams.lock().get(&packet.datagram_id).is_some_and(|datagram| datagram.address == src_addr)
+      || peers.lock().get(&src_addr).is_some_and(|peer| peer.recent_unreliable.contains(&packet.datagram_id))
+      || sessions.lock().get(&src_addr).is_some_and(|state| state.id == packet.datagram_id && state.status == SessionStatus::Connecting);
+    if !is_ours {
+      return;
+    }
+
+    socket.sock_send(AckUdpPacket::new_cookie(packet.datagram_id, &packet.payload, PacketFlags::COOKIE | PacketFlags::ACK), src_addr);
+
+    // Every segment in flight got the same challenge instead of being reassembled, they're resent once per cookie
+    let is_new = {
+      let mut peers = peers.lock();
+      let peer = peers.entry(src_addr).or_insert_with(|| AckUdpPeer::new(config));
+      let is_new = peer.cookie.as_ref() != Some(&packet.payload);
+      peer.cookie = Some(packet.payload.clone());
+
+      is_new
+    };
+    if !is_new {
+      return;
+    }
+
+    let freed: usize = pending_out_datagrams.lock()
+      .values()
+      .filter(|datagram| datagram.address == src_addr)
+      .map(|datagram| datagram.mark_lost())
+      .sum();
+    if freed > 0 {
+      let mut peers = peers.lock();
+      let peer = peers.entry(src_addr).or_insert_with(|| AckUdpPeer::new(config));
+      peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(freed);
+    }
+
+    AckUdp::transmit_pending(shared, src_addr);
+  }
+}
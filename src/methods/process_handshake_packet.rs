@@ -0,0 +1,43 @@
+use std::net::SocketAddr;
+
+use crate::{
+  AckUdp,
+  types::AckUdpPacket,
+  sock_send::{Sealing, SockSend},
+  peer::AckUdpPeer,
+  shared::AckUdpShared
+};
+
+impl AckUdp {
+  // Noise handshake messages. Packets sent to the peer before its keys were ready never left,
+  // so once they are everything in flight is resent without counting it as congestion.
+  pub(crate) fn process_handshake_packet(shared: &AckUdpShared, src_addr: SocketAddr, packet: &AckUdpPacket) {
+    let AckUdpShared { config, sock: socket, pending_out_datagrams, peers, .. } = shared;
+    let Sealing::Noise(noise) = &socket.sealing else {
+      return;
+    };
+
+    let outcome = noise.process(config, src_addr, packet);
+    if let Some(reply) = outcome.reply {
+      socket.sock_send_plain(reply, src_addr);
+    }
+    if !outcome.completed {
+      return;
+    }
+    // The handshake can't complete without seeing what we sent
+    peers.lock().entry(src_addr).or_insert_with(|| AckUdpPeer::new(config)).validated = true;
+
+    let freed: usize = pending_out_datagrams.lock()
+      .values()
+      .filter(|datagram| datagram.address == src_addr)
+      .map(|datagram| datagram.mark_lost())
+      .sum();
+    if freed > 0 {
+      let mut peers = peers.lock();
+      let peer = peers.entry(src_addr).or_insert_with(|| AckUdpPeer::new(config));
+      peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(freed);
+    }
+
+    AckUdp::transmit_pending(shared, src_addr);
+  }
+}
@@ -1,128 +1,439 @@
-use std::{sync::Arc, collections::{VecDeque, HashSet, HashMap}, net::SocketAddr};
+use std::{sync::{atomic::Ordering, Arc}, collections::{BTreeSet, HashSet, HashMap}, net::SocketAddr};
 
-use chrono::Utc;
 use parking_lot::Mutex;
-use tokio::{sync::mpsc::Receiver, net::UdpSocket};
+use tokio::{sync::mpsc::Receiver, time::Instant};
 
-use crate::{AckUdp, types::{AckUdpPacket, AckUdpDatagram, AckUdpDatagramOutStatusEnum, AckUdpDatagramOutStatus}, sock_send::SockSend};
+use crate::{
+  AckUdp, 
+  types::{AckUdpPacket, PacketFlags, AckUdpSack, AckUdpDatagram, AckUdpDatagrams, AckUdpDeliveredWindows, AckUdpBufferedBytes, IncomingPacket, OrderedInbox, RejectReason, HEADER_SIZE}, 
+  sock_send::{AckUdpSocket, SockSend},
+  peer::AckUdpPeer,
+  noise::PublicKey,
+  shared::AckUdpShared,
+  AckUdpConfig
+};
 
 impl AckUdp {
-  pub async fn process_packets(
-    mut incoming_queue_receiver: Receiver<()>, 
-    incoming_queue: Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>,
-    socket: Arc<UdpSocket>,
-    ready_to_read_datagrams: Arc<Mutex<VecDeque<(SocketAddr, Vec<u8>)>>>,
-    pending_in_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-    pending_out_datagrams: Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>,
-    out_datagrams_status_links: Arc<Mutex<HashMap<[u8; 5], Arc<Mutex<AckUdpDatagramOutStatus>>>>>,
-  ) {
+  pub(crate) async fn process_packets(shared: Arc<AckUdpShared>, mut incoming_receiver: Receiver<IncomingPacket>) {
     loop {
-      if let Ok(()) = incoming_queue_receiver.try_recv() {
-        break;
+      let (src_addr, packet) = tokio::select! {
+        _ = shared.cancellation_token.cancelled() => break,
+        incoming = incoming_receiver.recv() => match incoming {
+          Some(v) => v,
+          None => break
+        }
+      };
+
+      AckUdp::process_packet(&shared, src_addr, packet);
+    }
+  }
+
+  pub(crate) fn process_packet(shared: &AckUdpShared, src_addr: SocketAddr, mut packet: AckUdpPacket) {
+    let AckUdpShared { 
+      config, 
+      sock: socket, 
+      stats, 
+      cookies, 
+      buffered_in_bytes, 
+      pending_in_datagrams, 
+      delivered_datagrams, 
+      ordered_inboxes, 
+      pending_out_datagrams, 
+      out_datagrams_status_links, 
+      peers, 
+      .. 
+    } = shared;
+
+    if packet.flags.contains(PacketFlags::HANDSHAKE) {
+      AckUdp::process_handshake_packet(shared, src_addr, &packet);
+
+      return;
+    }
+
+    if packet.flags.contains(PacketFlags::COOKIE) {
+      AckUdp::process_cookie_packet(shared, src_addr, &packet);
+
+      return;
+    }
+
+    let is_data = packet.flags.is_data();
+    let reliable = is_data && !packet.flags.contains(PacketFlags::UNRELIABLE);
+    // Sizes our ACKs and NACKs so they fit the peer's receive buffer
+    let (segment_size, validated) = match peers.lock().get_mut(&src_addr) {
+      Some(peer) => {
+        peer.last_heard = Instant::now();
+        (peer.segment_size, peer.validated)
       }
-  
-      let v = incoming_queue.lock().pop_front();
-      if v.is_none() {
-        continue;
+      None => (config.segment_size, false)
+    };
+    // The source of an unvalidated packet may be spoofed, replies to it must not amplify what it sent.
+    // SACKs are trimmed to fit, other replies that don't are skipped.
+    let mut budget = (config.cookie_challenge && !validated)
+      .then(|| config.amplification_factor * (HEADER_SIZE + packet.payload.len()));
+    let segment_size = match budget {
+      Some(budget) => segment_size.min(budget.saturating_sub(HEADER_SIZE)),
+      None => segment_size
+    };
+
+    // Already delivered INcome type Datagram: our ACK got lost, so ACK the whole datagram again
+    if reliable && is_delivered(delivered_datagrams, src_addr, &packet.datagram_id) {
+      let sack = AckUdpSack::complete(packet.total_segments);
+      reply(socket, &mut budget, AckUdpPacket::new_ack(packet.datagram_id, receive_window(config, buffered_in_bytes), &sack), src_addr);
+
+      return;
+    }
+
+    // Reassembly state is only kept for addresses that proved they aren't spoofed, the others get a cookie to echo first
+    if is_data && packet.total_segments > 1 && budget.is_some() {
+      stats.cookie_challenges.fetch_add(1, Ordering::Relaxed);
+      reply(socket, &mut budget, AckUdpPacket::new_cookie(packet.datagram_id, &cookies.issue(src_addr), PacketFlags::COOKIE), src_addr);
+
+      return;
+    }
+
+    // Reassembly state is limited in size and number, a datagram that doesn't fit is refused for good
+    if is_data && packet.total_segments > 1 && !pending_in_datagrams.lock().contains_key(&packet.datagram_id) {
+      if let Err(reason) = AckUdp::admit_datagram(shared, src_addr, &packet) {
+        stats.rejected_datagrams.fetch_add(1, Ordering::Relaxed);
+        if reliable {
+          reply(socket, &mut budget, AckUdpPacket::new_rejected(packet.datagram_id, reason), src_addr);
+        }
+
+        return;
+      }
+    }
+
+    // No room left for a new segment: refuse it and let the sender know how much room there is.
+    // The sender retransmits it after the window reopens, unreliable segments are simply lost.
+    if is_data && !has_room(config, buffered_in_bytes, pending_in_datagrams, &packet) {
+      if !reliable {
+        return;
       }
-      
-      let (src_addr, buf) = v.unwrap();
-      
-      let packet = AckUdpPacket::from(buf.to_vec());
-  
-      // Single INcome type Datagram
-      if packet.total_segments == 1 && packet.ack == 0 {
-        socket.sock_send(AckUdpPacket::new_ack(packet.datagram_id, vec![0]), src_addr);
-        ready_to_read_datagrams.lock().push_back((src_addr, packet.payload));
-  
-        continue;
+
+      let sack = pending_in_datagrams.lock().get(&packet.datagram_id)
+        .map(|datagram| datagram.received_sack(AckUdpSack::max_ranges(segment_size)))
+        .unwrap_or_default();
+      reply(socket, &mut budget, AckUdpPacket::new_ack(packet.datagram_id, receive_window(config, buffered_in_bytes), &sack), src_addr);
+
+      return;
+    }
+
+    // Single INcome type Datagram
+    if packet.total_segments == 1 && is_data {
+      buffered_in_bytes.fetch_add(packet.payload.len(), Ordering::Relaxed);
+      let payload = std::mem::take(&mut packet.payload);
+      let delivered = deliver(shared, src_addr, socket.sealing.remote_key(src_addr), &packet, payload);
+      if reliable {
+        let sack = AckUdpSack::complete(1);
+        reply(socket, &mut budget, complete_reply(shared, src_addr, &packet, delivered, &sack), src_addr);
       }
-  
-      // Splitted INcome type Datagram
-      if packet.total_segments > 1 && packet.ack == 0 {
-        if pending_in_datagrams.lock().contains_key(&packet.datagram_id) {
-          let mut datagram = pending_in_datagrams.lock().get(&packet.datagram_id).unwrap().clone();
-  
-          datagram.segments.lock().insert(packet.seg_index, packet.clone());
-          datagram.segments_got.lock().push(packet.seg_index);
-          datagram.last_active = Utc::now();
-  
-          let got_segments = datagram.segments.lock().len();
-          if packet.total_segments > 100 {
-            if got_segments % 100 == 0 || got_segments >= (datagram.segments_count - 10) as usize {
-              let last_packets = {
-                if datagram.segments_got.lock().len() <= 100 {
-                  datagram.clone().segments_got.lock().clone()
-                }
-                else {
-                  let start = datagram.segments_got.lock().len()-100;
-                  datagram.segments_got.lock()[start..].to_vec()
-                }
+
+      return;
+    }
+
+    // Splitted INcome type Datagram
+    // Unreliable ones are reassembled the same way, just never ACKed. If a segment is lost they expire.
+    if packet.total_segments > 1 && is_data {
+      // Looked up, updated and taken out once complete under one lock, so the expiry check can't remove it halfway
+      let updated = {
+        let mut pending_in_datagrams = pending_in_datagrams.lock();
+        match pending_in_datagrams.get_mut(&packet.datagram_id) {
+          Some(datagram) => {
+            if datagram.segments_count != packet.total_segments {
+              return;
+            }
+            // A segment that doesn't fit with the others gets the whole datagram refused
+            if let Err(reason) = datagram.admit_segment(&packet, config.max_datagram_size) {
+              Err((reason, pending_in_datagrams.remove(&packet.datagram_id)))
+            }
+            else {
+              // Segments are sent in order, so several later ones arriving first means the skipped ones got lost on the way
+              let lost = datagram.newly_lost(packet.seg_index, segment_size / 4);
+              if datagram.segments.lock().insert(packet.seg_index, packet.clone()).is_none() {
+                buffered_in_bytes.fetch_add(packet.buffered_size(), Ordering::Relaxed);
+              }
+              datagram.segments_got.lock().insert(packet.seg_index);
+              datagram.last_active = Instant::now();
+
+              // The SACK describes everything received so far, so a lost ACK is made up for by the next one
+              let sack = datagram.received_sack(AckUdpSack::max_ranges(segment_size));
+              let completed = if sack.cumulative == datagram.segments_count {
+                pending_in_datagrams.remove(&packet.datagram_id)
+              }
+              else {
+                None
               };
-              socket.sock_send(AckUdpPacket::new_ack(packet.datagram_id, last_packets), src_addr);
+
+              Ok((sack, lost, completed))
             }
           }
-          else {
-            socket.sock_send(AckUdpPacket::new_ack(packet.datagram_id, vec![packet.seg_index]), src_addr);
-          }
-  
-          if datagram.segments_count as usize == got_segments {
-            let payload = datagram.form_payload();
-            ready_to_read_datagrams.lock().push_back((src_addr, payload));
-            pending_in_datagrams.lock().remove(&packet.datagram_id);
-            socket.sock_send(AckUdpPacket::new_ack(packet.datagram_id, vec![packet.total_segments - 1]), src_addr);
+          None => {
+            buffered_in_bytes.fetch_add(packet.buffered_size(), Ordering::Relaxed);
+            let is_last = packet.seg_index + 1 == packet.total_segments;
+            let datagram = AckUdpDatagram { 
+              id: packet.datagram_id, 
+              address: src_addr,
+              segments_count: packet.total_segments, 
+              sequence: packet.sequence,
+              epoch: packet.epoch,
+              channel: packet.channel,
+              segments: Arc::new(Mutex::new(HashMap::from([(packet.seg_index, packet.clone())]))), 
+              segments_got: Arc::new(Mutex::new(BTreeSet::from([packet.seg_index]))),
+              segment_size: (!is_last).then_some(packet.payload.len()),
+              cumulative_ack: Arc::new(Mutex::new(0)),
+              segments_acks: Arc::new(Mutex::new(HashSet::new())), 
+              segments_sent: Arc::new(Mutex::new(HashMap::new())),
+              segments_pending: Arc::new(Mutex::new(BTreeSet::new())),
+              created_at: Instant::now(),
+              last_active: Instant::now(),
+              checks_failure_count: 0
+            };
+            let sack = datagram.received_sack(AckUdpSack::max_ranges(segment_size));
+            pending_in_datagrams.insert(packet.datagram_id, datagram);
+
+            Ok((sack, vec![], None))
           }
-          else {
-            pending_in_datagrams.lock().insert(packet.datagram_id, datagram);
+        }
+      };
+      let (sack, lost, completed) = match updated {
+        Ok(updated) => updated,
+        Err((reason, refused)) => {
+          buffered_in_bytes.fetch_sub(refused.map_or(0, |datagram| datagram.buffered_bytes()), Ordering::Relaxed);
+          stats.rejected_datagrams.fetch_add(1, Ordering::Relaxed);
+          if reliable {
+            reply(socket, &mut budget, AckUdpPacket::new_rejected(packet.datagram_id, reason), src_addr);
           }
+
+          return;
         }
-        else {
-          let segments = Arc::new(Mutex::new(HashMap::new()));
-          segments.lock().insert(packet.seg_index, packet.clone());
-  
-          let segments_got = Arc::new(Mutex::new(vec![packet.seg_index]));
-  
-          pending_in_datagrams.lock().insert(packet.datagram_id, AckUdpDatagram { 
-            id: packet.datagram_id, 
-            address: src_addr,
-            segments_count: packet.total_segments, 
-            segments, 
-            segments_got,
-            segments_acks: Arc::new(Mutex::new(HashSet::new())), 
-            last_active: Utc::now(),
-            checks_failure_count: 0
-          });
-
-          socket.sock_send(AckUdpPacket::new_ack(packet.datagram_id, vec![packet.seg_index]), src_addr);
+      };
+
+      if reliable && !lost.is_empty() {
+        reply(socket, &mut budget, AckUdpPacket::new_nack(packet.datagram_id, lost, segment_size), src_addr);
+      }
+
+      let Some(datagram) = completed else {
+        if reliable {
+          reply(socket, &mut budget, AckUdpPacket::new_ack(packet.datagram_id, receive_window(config, buffered_in_bytes), &sack), src_addr);
         }
-  
-        continue;
+
+        return;
+      };
+      let buffered = datagram.buffered_bytes();
+      let Some(payload) = datagram.form_payload() else {
+        // Every segment was counted as received, one that isn't there means the datagram is broken, not worth a panic
+        buffered_in_bytes.fetch_sub(buffered, Ordering::Relaxed);
+        return;
+      };
+      // The segments are gone, only the payload stays buffered until it's read
+      buffered_in_bytes.fetch_sub(buffered - payload.len(), Ordering::Relaxed);
+      let delivered = deliver(shared, src_addr, socket.sealing.remote_key(src_addr), &packet, payload);
+      if reliable {
+        reply(socket, &mut budget, complete_reply(shared, src_addr, &packet, delivered, &sack), src_addr);
+      }
+
+      return;
+    }
+
+    // A PING | ACK has nothing to do beyond marking the peer alive above
+    if packet.flags.contains(PacketFlags::PING) {
+      if !packet.flags.contains(PacketFlags::ACK) {
+        reply(socket, &mut budget, AckUdpPacket::new_ping(PacketFlags::PING | PacketFlags::ACK), src_addr);
       }
-  
-      // Received ACK packet for one of segments
-      if packet.ack == 1 {
-        if pending_out_datagrams.lock().contains_key(&packet.datagram_id) {
-          let mut datagram = pending_out_datagrams.lock().get(&packet.datagram_id).unwrap().to_owned();
-  
-          let acks = packet.get_acks();
-          let is_full_ack = datagram.ack_segment(acks);
-
-          if is_full_ack {
-            pending_out_datagrams.lock().remove(&packet.datagram_id);
-            if out_datagrams_status_links.lock().contains_key(&packet.datagram_id) {
-              out_datagrams_status_links.lock().get(&packet.datagram_id).unwrap().lock().0 = AckUdpDatagramOutStatusEnum::Succeeded;
-              out_datagrams_status_links.lock().remove(&packet.datagram_id);
+
+      return;
+    }
+
+    if packet.flags.contains(PacketFlags::REJECTED) {
+      AckUdp::process_rejected_packet(shared, src_addr, &packet);
+
+      return;
+    }
+
+    if packet.flags.contains(PacketFlags::CONNECT) || packet.flags.contains(PacketFlags::CLOSE) {
+      AckUdp::process_session_packet(shared, src_addr, &packet);
+
+      return;
+    }
+
+    // Received ACK packet for one of segments
+    // ACKs that don't match one of our datagrams to that address are ignored, anyone can send those
+    if packet.flags.contains(PacketFlags::ACK) {
+      let datagram = pending_out_datagrams.lock().get(&packet.datagram_id).cloned();
+      let sack = packet.get_acks();
+      if let (Some(mut datagram), Some(sack)) = (datagram.filter(|datagram| datagram.address == src_addr), sack) {
+        let acks = datagram.sack_segments(&sack);
+        let rtt = datagram.rtt_sample(&acks);
+        let (is_full_ack, freed) = datagram.ack_segment(acks);
+
+        {
+          let mut peers = peers.lock();
+          let peer = peers.entry(src_addr).or_insert_with(|| AckUdpPeer::new(config));
+          // Only the receiver of the datagram knows its id
+          peer.validated = true;
+          if let Some(window) = packet.get_window() {
+            peer.receive_window = window as usize;
+          }
+          if let Some(rtt) = rtt {
+            peer.rtt.sample(rtt);
+            if let Some(estimate) = peer.rtt.estimate() {
+              peer.congestion.on_rtt(estimate.srtt);
             }
           }
-          else {
+          if freed > 0 {
+            peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(freed);
+            peer.congestion.on_ack(freed);
+          }
+        }
+
+        if is_full_ack {
+          pending_out_datagrams.lock().remove(&packet.datagram_id);
+          if let Some(status) = out_datagrams_status_links.lock().remove(&packet.datagram_id) {
+            status.lock().succeed();
+          }
+        }
+        else if freed > 0 {
+          if let Some(datagram) = pending_out_datagrams.lock().get_mut(&packet.datagram_id) {
             datagram.checks_failure_count = 0;
-            datagram.last_active = Utc::now();
-            pending_out_datagrams.lock().insert(packet.datagram_id, datagram);
+            datagram.last_active = Instant::now();
+          }
+        }
+
+        // ACKed bytes or a reopened receive window may let more segments out
+        AckUdp::transmit_pending(shared, src_addr);
+      }
+
+      return;
+    }
+
+    // Receiver noticed a gap: resend the missing segments right away instead of waiting for the timeout
+    if packet.flags.contains(PacketFlags::NACK) {
+      let datagram = pending_out_datagrams.lock().get(&packet.datagram_id).cloned();
+      if let Some(datagram) = datagram.filter(|datagram| datagram.address == src_addr) {
+        let nacks = packet.get_nacks();
+        let lost = datagram.mark_lost_segments(&nacks);
+        if lost > 0 {
+          let mut peers = peers.lock();
+          let peer = peers.entry(src_addr).or_insert_with(|| AckUdpPeer::new(config));
+          peer.congestion.on_loss(peer.bytes_in_flight);
+          peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(lost);
+
+          // Retransmissions replace what was just taken out of flight, so they don't wait for the congestion window
+          for seg_index in nacks {
+            if let Some(segment) = datagram.take_pending(seg_index) {
+              peer.bytes_in_flight += segment.payload.len();
+              peer.congestion.on_sent(segment.payload.len());
+              socket.sock_send(segment.into(), src_addr);
+            }
           }
         }
-  
-        continue;
       }
+
+      return;
+    }
+
+    // The sender gave up on an ordered datagram, stop holding later ones back for it.
+    // Anyone can send one, so it only counts in inboxes the sender's datagrams already created.
+    if packet.flags.contains(PacketFlags::DROPPED) {
+      let released = ordered_inboxes.lock()
+        .get_mut(&(src_addr, packet.channel))
+        .map(|inbox| inbox.skip(packet.epoch, packet.sequence))
+        .unwrap_or_default();
+      push_ready(shared, src_addr, packet.channel, socket.sealing.remote_key(src_addr), released);
+    }
+  }
+}
+
+fn reply(socket: &Arc<AckUdpSocket>, budget: &mut Option<usize>, buf: Vec<u8>, address: SocketAddr) {
+  if let Some(budget) = budget {
+    if buf.len() > *budget {
+      return;
+    }
+    *budget -= buf.len();
+  }
+
+  socket.sock_send(buf, address);
+}
+
+fn is_delivered(delivered_datagrams: &AckUdpDeliveredWindows, address: SocketAddr, id: &[u8; 5]) -> bool {
+  delivered_datagrams.lock().get(&address).map(|window| window.contains(id)).unwrap_or(false)
+}
+
+fn mark_delivered(delivered_datagrams: &AckUdpDeliveredWindows, config: &AckUdpConfig, address: SocketAddr, id: [u8; 5]) {
+  delivered_datagrams.lock().entry(address).or_default().insert(id, config.dedup_window_size);
+}
+
+// Bytes we can still buffer, advertised in every ACK
+fn receive_window(config: &AckUdpConfig, buffered_in_bytes: &AckUdpBufferedBytes) -> u32 {
+  config.receive_window.saturating_sub(buffered_in_bytes.load(Ordering::Relaxed)) as u32
+}
+
+// Segments we already hold don't take any more room
+fn has_room(config: &AckUdpConfig, buffered_in_bytes: &AckUdpBufferedBytes, pending_in_datagrams: &AckUdpDatagrams, packet: &AckUdpPacket) -> bool {
+  let is_held = pending_in_datagrams.lock().get(&packet.datagram_id)
+    .map(|datagram| datagram.segments.lock().contains_key(&packet.seg_index))
+    .unwrap_or(false);
+
+  is_held || buffered_in_bytes.load(Ordering::Relaxed) + packet.buffered_size() <= config.receive_window
+}
+
+// Final answer to a reliable datagram once it's reassembled. One that was discarded to keep the order is refused,
+// ACKing it would tell the sender it got through.
+fn complete_reply(shared: &AckUdpShared, address: SocketAddr, packet: &AckUdpPacket, delivered: bool, sack: &AckUdpSack) -> Vec<u8> {
+  if !delivered {
+    return AckUdpPacket::new_rejected(packet.datagram_id, RejectReason::Late);
+  }
+
+  mark_delivered(&shared.delivered_datagrams, &shared.config, address, packet.datagram_id);
+  AckUdpPacket::new_ack(packet.datagram_id, receive_window(&shared.config, &shared.buffered_in_bytes), sack)
+}
+
+// Hands a reassembled datagram to `recv`. Ordered ones wait until every earlier sequence number is read or dropped,
+// sequenced unreliable ones are discarded if a newer one was read already. Returns whether it wasn't discarded.
+fn deliver(shared: &AckUdpShared, address: SocketAddr, remote_key: Option<PublicKey>, packet: &AckUdpPacket, payload: Vec<u8>) -> bool {
+  if packet.sequence == 0 {
+    push_ready(shared, address, packet.channel, remote_key, vec![payload]);
+    return true;
+  }
+
+  let size = payload.len();
+  let (released, delivered) = {
+    let mut ordered_inboxes = shared.ordered_inboxes.lock();
+    let inbox = ordered_inboxes.entry((address, packet.channel)).or_insert_with(|| OrderedInbox::new(packet.epoch));
+    // Whatever was held for the sender's previous run goes first. A straggler of an earlier run is late.
+    inbox.restart(packet.epoch).map(|mut released| {
+      let current = if !packet.flags.contains(PacketFlags::UNRELIABLE) {
+        inbox.push(packet.sequence, payload)
+      }
+      else {
+        inbox.accept_newer(packet.sequence).then(|| vec![payload])
+      };
+      let delivered = current.is_some();
+      released.extend(current.unwrap_or_default());
+
+      (released, delivered)
+    }).unwrap_or_default()
+  };
+  // Reading it now would break the order
+  if !delivered {
+    shared.buffered_in_bytes.fetch_sub(size, Ordering::Relaxed);
+  }
+  push_ready(shared, address, packet.channel, remote_key, released);
+
+  delivered
+}
+
+pub fn push_ready(shared: &AckUdpShared, address: SocketAddr, channel: u8, remote_key: Option<PublicKey>, payloads: Vec<Vec<u8>>) {
+  if payloads.is_empty() {
+    return;
+  }
+
+  {
+    let mut ready_to_read_datagrams = shared.ready_to_read_datagrams.lock();
+    for payload in payloads {
+      ready_to_read_datagrams.push(address, channel, remote_key, payload);
     }
   }
-}
\ No newline at end of file
+  // Every reader wakes up, each of them may be waiting on a different channel
+  shared.ready_to_read_notify.notify_waiters();
+}
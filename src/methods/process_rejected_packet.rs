@@ -0,0 +1,38 @@
+use std::net::SocketAddr;
+
+use crate::{
+  AckUdp,
+  types::{AckUdpPacket, SendError},
+  sock_send::SockSend,
+  shared::AckUdpShared
+};
+
+impl AckUdp {
+  // The receiver refused one of our datagrams, resending it would be refused as well
+  pub(crate) fn process_rejected_packet(shared: &AckUdpShared, src_addr: SocketAddr, packet: &AckUdpPacket) {
+    let AckUdpShared { sock: socket, pending_out_datagrams, out_datagrams_status_links, peers, .. } = shared;
+    let datagram = {
+      let mut pending_out_datagrams = pending_out_datagrams.lock();
+      match pending_out_datagrams.get(&packet.datagram_id) {
+        Some(datagram) if datagram.address == src_addr => pending_out_datagrams.remove(&packet.datagram_id),
+        _ => None
+      }
+    };
+    let Some(datagram) = datagram else {
+      return;
+    };
+
+    if let Some(peer) = peers.lock().get_mut(&src_addr) {
+      peer.bytes_in_flight = peer.bytes_in_flight.saturating_sub(datagram.bytes_in_flight());
+    }
+    // Later ordered datagrams would otherwise wait for it until `incoming_expiry`
+    if datagram.sequence != 0 {
+      socket.sock_send(AckUdpPacket::new_dropped(datagram.id, datagram.channel, datagram.sequence, datagram.epoch), src_addr);
+    }
+    if let Some(status) = out_datagrams_status_links.lock().remove(&datagram.id) {
+      status.lock().fail(SendError::Rejected(packet.get_reject_reason()));
+    }
+
+    AckUdp::transmit_pending(shared, src_addr);
+  }
+}
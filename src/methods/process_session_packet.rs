@@ -0,0 +1,103 @@
+use std::{sync::atomic::Ordering, net::SocketAddr};
+
+use crate::{
+  AckUdp,
+  types::{AckUdpPacket, PacketFlags, HEADER_SIZE},
+  sock_send::SockSend,
+  peer::{AckUdpPeer, AckUdpPeers},
+  session::{AckUdpSessionState, SessionStatus},
+  shared::AckUdpShared,
+  AckUdpConfig
+};
+
+impl AckUdp {
+  // Handshake and close packets of the session layer
+  pub(crate) fn process_session_packet(shared: &AckUdpShared, src_addr: SocketAddr, packet: &AckUdpPacket) {
+    let AckUdpShared { cancellation_token, config, sock: socket, stats, cookies, sessions, accept_queue, accept_notify, peers, .. } = shared;
+    let session_id = packet.datagram_id;
+    let is_reply = packet.flags.contains(PacketFlags::ACK);
+
+    if packet.flags.contains(PacketFlags::CONNECT) {
+      let Some(peer_segment_size) = packet.get_segment_size() else {
+        return;
+      };
+      let segment_size = config.segment_size.min(peer_segment_size);
+      let mut sessions = sessions.lock();
+
+      // Our CONNECT got accepted
+      if is_reply {
+        if let Some(state) = sessions.get_mut(&src_addr).filter(|state| state.id == session_id && state.status == SessionStatus::Connecting) {
+          state.status = SessionStatus::Established;
+          state.segment_size = segment_size;
+          state.changed.notify_waiters();
+          set_segment_size(config, peers, src_addr, segment_size);
+        }
+
+        return;
+      }
+
+      // Our CONNECT | ACK got lost
+      if let Some(state) = sessions.get(&src_addr).filter(|state| state.id == session_id) {
+        if state.status == SessionStatus::Established {
+          socket.sock_send(AckUdpPacket::new_connect(session_id, config.segment_size, PacketFlags::CONNECT | PacketFlags::ACK), src_addr);
+        }
+
+        return;
+      }
+
+      if !config.accept_sessions || accept_queue.lock().len() >= config.session_backlog {
+        socket.sock_send(AckUdpPacket::new_close(session_id, PacketFlags::CLOSE | PacketFlags::ACK), src_addr);
+        return;
+      }
+
+      // A session is only set up for addresses that proved they aren't spoofed, the others get a cookie to echo first.
+      // The CONNECT they resend afterwards is accepted.
+      let validated = peers.lock().get(&src_addr).is_some_and(|peer| peer.validated);
+      if config.cookie_challenge && !validated {
+        let challenge = AckUdpPacket::new_cookie(session_id, &cookies.issue(src_addr), PacketFlags::COOKIE);
+        if challenge.len() <= config.amplification_factor * (HEADER_SIZE + packet.payload.len()) {
+          stats.cookie_challenges.fetch_add(1, Ordering::Relaxed);
+          socket.sock_send(challenge, src_addr);
+        }
+
+        return;
+      }
+
+      // The peer restarted or connects again, whatever we had with it is over
+      let state = AckUdpSessionState::new(session_id, SessionStatus::Established, segment_size, cancellation_token.child_token());
+      if let Some(previous) = sessions.insert(src_addr, state) {
+        previous.close();
+      }
+      set_segment_size(config, peers, src_addr, segment_size);
+      socket.sock_send(AckUdpPacket::new_connect(session_id, config.segment_size, PacketFlags::CONNECT | PacketFlags::ACK), src_addr);
+
+      accept_queue.lock().push_back((src_addr, session_id));
+      accept_notify.notify_waiters();
+
+      return;
+    }
+
+    if packet.flags.contains(PacketFlags::CLOSE) {
+      let mut sessions = sessions.lock();
+      let is_ours = sessions.get(&src_addr).is_some_and(|state| {
+        // A reply either confirms our CLOSE or refuses our CONNECT
+        state.id == session_id && (!is_reply || state.status != SessionStatus::Established)
+      });
+      if is_ours {
+        if let Some(state) = sessions.remove(&src_addr) {
+          state.close();
+        }
+      }
+
+      // Answered even for sessions we don't know anymore, the first answer may have been lost
+      if !is_reply {
+        socket.sock_send(AckUdpPacket::new_close(session_id, PacketFlags::CLOSE | PacketFlags::ACK), src_addr);
+      }
+    }
+  }
+}
+
+// Both sides send segments no bigger than the smaller one of their segment sizes
+fn set_segment_size(config: &AckUdpConfig, peers: &AckUdpPeers, address: SocketAddr, segment_size: usize) {
+  peers.lock().entry(address).or_insert_with(|| AckUdpPeer::new(config)).segment_size = segment_size;
+}
@@ -0,0 +1,40 @@
+use std::{sync::atomic::Ordering, net::SocketAddr};
+
+use tokio_util::sync::CancellationToken;
+
+use crate::{shared::AckUdpShared, noise::PublicKey, AckUdp};
+
+impl AckUdp {
+  // Waits for the oldest reassembled datagram on `channel` (any if `None`) whose source `filter` accepts.
+  // Returns `None` once `cancellation_token` is cancelled and nothing accepted is left.
+  pub(crate) async fn wait_ready(
+    shared: &AckUdpShared,
+    cancellation_token: &CancellationToken,
+    channel: Option<u8>,
+    filter: impl Fn(SocketAddr) -> bool
+  ) -> Option<(SocketAddr, Option<PublicKey>, Vec<u8>)> {
+    loop {
+      let notified = shared.ready_to_read_notify.notified();
+      if let Some(datagram) = AckUdp::take_ready(shared, channel, &filter) {
+        return Some(datagram);
+      }
+      if cancellation_token.is_cancelled() {
+        return None;
+      }
+
+      tokio::select! {
+        _ = notified => {},
+        _ = cancellation_token.cancelled() => {}
+      }
+    }
+  }
+
+  pub(crate) fn take_ready(shared: &AckUdpShared, channel: Option<u8>, filter: impl Fn(SocketAddr) -> bool) -> Option<(SocketAddr, Option<PublicKey>, Vec<u8>)> {
+    let (address, remote_key, buf) = shared.ready_to_read_datagrams.lock().take(channel, filter)?;
+
+    // Frees room in the receive window we advertise
+    shared.buffered_in_bytes.fetch_sub(buf.len(), Ordering::Relaxed);
+
+    Some((address, remote_key, buf))
+  }
+}
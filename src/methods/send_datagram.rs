@@ -0,0 +1,85 @@
+use std::{collections::HashMap, sync::Arc, net::SocketAddr};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::{
+  types::{AckUdpDatagram, AckUdpDatagramOutStatus, AckUdpPacket, DeliveryHandle, PacketFlags},
+  sock_send::{AckUdpSocket, SockSend},
+  peer::AckUdpPeer,
+  channel::ChannelMode,
+  shared::AckUdpShared,
+  AckUdp
+};
+
+impl AckUdp {
+  // Queues `buf` with the guarantees `channel` is configured with, shared by `AckUdp` and `AckUdpSession`.
+  // Unreliable channels give no `DeliveryHandle`, there is nothing to wait for once the segments are handed to the socket.
+  pub(crate) fn send_datagram(shared: &AckUdpShared, channel: u8, buf: &[u8], address: SocketAddr) -> Option<DeliveryHandle> {
+    let AckUdpShared { config, sock: socket, pending_out_datagrams, out_datagrams_status_links, peers, .. } = shared;
+    let mode = config.channel_mode(channel);
+    let datagram_id = rand::thread_rng().gen::<[u8; 5]>();
+    let in_flight = pending_out_datagrams.lock().values()
+      .any(|datagram| datagram.address == address && datagram.channel == channel && datagram.sequence != 0);
+    let (sequence, segment_size) = {
+      let mut peers = peers.lock();
+      let peer = peers.entry(address).or_insert_with(|| AckUdpPeer::new(config));
+      peer.last_sent = Instant::now();
+      if !mode.is_reliable() {
+        peer.sent_unreliable(datagram_id);
+      }
+      let sequence = if mode.is_sequenced() || (mode == ChannelMode::ReliableUnordered && peer.ordered) {
+        peer.next_sequence(config, channel, in_flight)
+      }
+      else {
+        (0, 0)
+      };
+
+      (sequence, peer.segment_size)
+    };
+
+    if !mode.is_reliable() {
+      let segments = AckUdpPacket::split(buf, segment_size, datagram_id, channel, sequence, PacketFlags::UNRELIABLE);
+      AckUdp::transmit_unreliable(socket, segments, address);
+      return None;
+    }
+
+    let segments = AckUdpPacket::split(buf, segment_size, datagram_id, channel, sequence, PacketFlags::empty());
+    let segments_count = segments.len() as u32;
+
+    let status = Arc::new(Mutex::new(AckUdpDatagramOutStatus::new(datagram_id, address, segments_count)));
+    pending_out_datagrams.lock().insert(datagram_id, AckUdpDatagram::new_out(datagram_id, address, channel, sequence, segments));
+    out_datagrams_status_links.lock().insert(datagram_id, status.clone());
+
+    // Segments go out as the peer's congestion window allows, the rest follow as ACKs come in
+    AckUdp::transmit_pending(shared, address);
+
+    Some(DeliveryHandle::new(status))
+  }
+
+  // Fire and forget on channel 0, shared by `AckUdp` and `AckUdpSession`. The id is remembered so a cookie
+  // challenge for it gets echoed.
+  pub(crate) fn send_unreliable_datagram(shared: &AckUdpShared, buf: &[u8], address: SocketAddr) {
+    let datagram_id = rand::thread_rng().gen::<[u8; 5]>();
+    let segment_size = {
+      let mut peers = shared.peers.lock();
+      let peer = peers.entry(address).or_insert_with(|| AckUdpPeer::new(&shared.config));
+      peer.last_sent = Instant::now();
+      peer.sent_unreliable(datagram_id);
+
+      peer.segment_size
+    };
+    let segments = AckUdpPacket::split(buf, segment_size, datagram_id, 0, (0, 0), PacketFlags::UNRELIABLE);
+    AckUdp::transmit_unreliable(&shared.sock, segments, address);
+  }
+
+  pub(crate) fn transmit_unreliable(socket: &Arc<AckUdpSocket>, mut segments: HashMap<u32, AckUdpPacket>, address: SocketAddr) {
+    // In order, the receiver can't ask for missing ones anyway
+    for index in 0..segments.len() as u32 {
+      if let Some(segment) = segments.remove(&index) {
+        socket.sock_send(segment.into(), address);
+      }
+    }
+  }
+}
@@ -0,0 +1,79 @@
+use std::{net::SocketAddr, collections::{BTreeMap, HashSet, VecDeque}};
+
+use tokio::time::Instant;
+
+use crate::{
+  types::AckUdpDatagram, 
+  sock_send::SockSend, 
+  peer::AckUdpPeer, 
+  shared::AckUdpShared,
+  AckUdp
+};
+
+impl AckUdp {
+  // Sends pending segments of `address` for as long as its receive window and congestion controller allow.
+  // Channels take turns segment by segment so a big transfer on one doesn't hold the others back,
+  // within a channel the oldest datagram goes first.
+  pub(crate) fn transmit_pending(shared: &AckUdpShared, address: SocketAddr) {
+    let AckUdpShared { config, sock: socket, pending_out_datagrams, peers, .. } = shared;
+    let mut datagrams: Vec<_> = pending_out_datagrams.lock()
+      .values()
+      .filter(|datagram| datagram.address == address)
+      .cloned()
+      .collect();
+    if datagrams.is_empty() {
+      return;
+    }
+    datagrams.sort_by_key(|datagram| datagram.created_at);
+
+    let mut channels: BTreeMap<u8, VecDeque<AckUdpDatagram>> = BTreeMap::new();
+    for datagram in datagrams {
+      channels.entry(datagram.channel).or_default().push_back(datagram);
+    }
+
+    let mut peers = peers.lock();
+    let peer = peers.entry(address).or_insert_with(|| AckUdpPeer::new(config));
+
+    let mut started = HashSet::new();
+    'rounds: while !channels.is_empty() {
+      for queue in channels.values_mut() {
+        while let Some(datagram) = queue.front() {
+          let Some((seg_index, bytes)) = datagram.next_pending() else {
+            queue.pop_front();
+            continue;
+          };
+          if !peer.window_allows(bytes) || !peer.congestion.can_send(peer.bytes_in_flight, bytes) {
+            break 'rounds;
+          }
+
+          let was_idle = !datagram.has_in_flight();
+          // Got ACKed while we were looking at it
+          let Some(packet) = datagram.take_pending(seg_index) else {
+            continue;
+          };
+          if was_idle {
+            started.insert(datagram.id);
+          }
+
+          let packet_bytes: Vec<u8> = packet.into();
+          socket.sock_send(packet_bytes, address);
+
+          peer.bytes_in_flight += bytes;
+          peer.congestion.on_sent(bytes);
+          break;
+        }
+      }
+
+      channels.retain(|_, queue| !queue.is_empty());
+    }
+    drop(peers);
+
+    // The retransmission timer starts with the first segment in flight, not when the datagram was queued
+    let mut pending_out_datagrams = pending_out_datagrams.lock();
+    for id in started {
+      if let Some(datagram) = pending_out_datagrams.get_mut(&id) {
+        datagram.last_active = Instant::now();
+      }
+    }
+  }
+}
@@ -0,0 +1,382 @@
+use std::{collections::HashMap, fmt, net::SocketAddr};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use snow::{params::DHChoice, resolvers::{CryptoResolver, DefaultResolver}, Builder, HandshakeState};
+use tokio::time::Instant;
+
+use crate::{crypto::PacketCipher, types::{AckUdpPacket, HEADER_SIZE}, AckUdpConfig, AckUdpError};
+
+// Mutual authentication with static keys neither side has to know in advance
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PROLOGUE: &[u8] = b"ack-udp noise v1";
+// Biggest handshake message of the XX pattern (the second one) with an empty payload
+pub const MAX_HANDSHAKE_MESSAGE: usize = 96;
+// The first message (32 bytes) is padded to the size of the answer, so answering it never sends more than was received
+const FIRST_MESSAGE_PADDING: usize = MAX_HANDSHAKE_MESSAGE - 32;
+// Handshakes going on at once. Anyone can start one, the one started longest ago makes room for a new one.
+pub const MAX_PENDING_HANDSHAKES: usize = 1024;
+
+pub type PublicKey = [u8; 32];
+
+// Static X25519 key pair identifying an endpoint. The private half is kept out of `Debug` output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct NoiseKeypair {
+  pub private: [u8; 32],
+  pub public: PublicKey,
+}
+
+impl NoiseKeypair {
+  pub fn generate() -> Self {
+    let keypair = noise_builder().generate_keypair().expect("default resolver supports X25519");
+
+    NoiseKeypair { private: to_key(&keypair.private), public: to_key(&keypair.public) }
+  }
+
+  pub fn from_private_key(private: [u8; 32]) -> Self {
+    let mut dh = DefaultResolver.resolve_dh(&DHChoice::Curve25519).expect("default resolver supports X25519");
+    dh.set(&private);
+
+    NoiseKeypair { private, public: to_key(dh.pubkey()) }
+  }
+}
+
+impl fmt::Debug for NoiseKeypair {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.debug_struct("NoiseKeypair").field("public", &self.public).finish_non_exhaustive()
+  }
+}
+
+// Keys agreed on with one peer
+struct NoisePeer {
+  cipher: PacketCipher,
+  remote_key: PublicKey,
+  handshake_id: [u8; 5],
+  last_message: Option<Vec<u8>>, // Our final handshake message, resent if the peer didn't get it
+}
+
+struct PendingHandshake {
+  id: [u8; 5],
+  state: HandshakeState,
+  initiator: bool,
+  last_message: Vec<u8>, // Resent until the peer answers
+  budget: usize, // Bytes a responder may still send, `amplification_factor` times what the initiator sent
+  started_at: Instant,
+  sent_at: Instant,
+  attempts: u16,
+}
+
+#[derive(Default)]
+struct NoiseState {
+  established: HashMap<SocketAddr, NoisePeer>,
+  pending: HashMap<SocketAddr, PendingHandshake>,
+}
+
+// What a handshake packet led to
+#[derive(Default)]
+pub struct HandshakeOutcome {
+  pub reply: Option<Vec<u8>>,
+  pub completed: bool, // Keys with the peer are ready
+}
+
+// Noise handshakes and the per-peer keys they end up with. Handshake messages travel in HANDSHAKE packets,
+// `seg_index` tells which of the three messages it is and the datagram id identifies the handshake.
+pub struct NoiseSessions {
+  keypair: NoiseKeypair,
+  state: Mutex<NoiseState>,
+}
+
+impl NoiseSessions {
+  pub fn new(keypair: NoiseKeypair) -> Self {
+    NoiseSessions { keypair, state: Mutex::new(NoiseState::default()) }
+  }
+
+  pub fn remote_key(&self, address: SocketAddr) -> Option<PublicKey> {
+    self.state.lock().established.get(&address).map(|peer| peer.remote_key)
+  }
+
+  // `None` until the handshake with `address` is done
+  pub fn seal(&self, address: SocketAddr, raw_packet: Vec<u8>) -> Option<Vec<u8>> {
+    self.state.lock().established.get(&address).map(|peer| peer.cipher.seal(raw_packet))
+  }
+
+  pub fn open(&self, address: SocketAddr, packet: &mut AckUdpPacket, raw_packet: &[u8]) -> Result<(), AckUdpError> {
+    match self.state.lock().established.get(&address) {
+      Some(peer) => peer.cipher.open(packet, raw_packet),
+      None => Err(AckUdpError::Unauthenticated)
+    }
+  }
+
+  // First handshake message for `address`, `None` if a handshake with it is already going on
+  pub fn initiate(&self, address: SocketAddr) -> Option<Vec<u8>> {
+    let mut state = self.state.lock();
+    if state.pending.contains_key(&address) {
+      return None;
+    }
+
+    let id = rand::thread_rng().gen::<[u8; 5]>();
+    let mut handshake = noise_builder().local_private_key(&self.keypair.private).build_initiator().ok()?;
+    let message = write_message(&mut handshake, id, 0, &[0; FIRST_MESSAGE_PADDING])?;
+    make_room(&mut state.pending);
+    state.pending.insert(address, PendingHandshake::new(id, handshake, true, message.clone()));
+
+    Some(message)
+  }
+
+  // The source of a first message may be spoofed, a responder never sends more than `amplification_factor` times
+  // what it got for the handshake
+  pub fn process(&self, config: &AckUdpConfig, address: SocketAddr, packet: &AckUdpPacket) -> HandshakeOutcome {
+    let mut state = self.state.lock();
+    let state = &mut *state;
+    let id = packet.datagram_id;
+    let received = config.amplification_factor.saturating_mul(HEADER_SIZE + packet.payload.len());
+    let mut outcome = HandshakeOutcome::default();
+
+    match packet.seg_index {
+      // -> e
+      0 => {
+        if let Some(pending) = state.pending.get_mut(&address) {
+          // Our answer got lost
+          if pending.id == id && !pending.initiator {
+            pending.budget = pending.budget.saturating_add(received);
+            outcome.reply = pending.spend().then(|| pending.last_message.clone());
+            return outcome;
+          }
+          // Both sides started at once, the handshake with the greater id goes on
+          if pending.initiator && pending.id > id {
+            return outcome;
+          }
+        }
+
+        let Ok(mut handshake) = noise_builder().local_private_key(&self.keypair.private).build_responder() else {
+          return outcome;
+        };
+        let mut payload = [0; MAX_HANDSHAKE_MESSAGE];
+        if handshake.read_message(&packet.payload, &mut payload).is_err() {
+          return outcome;
+        }
+        // <- e, ee, s, es
+        if let Some(message) = write_message(&mut handshake, id, 1, &[]) {
+          let mut pending = PendingHandshake::new(id, handshake, false, message);
+          pending.budget = received;
+          outcome.reply = pending.spend().then(|| pending.last_message.clone());
+          if !state.pending.contains_key(&address) {
+            make_room(&mut state.pending);
+          }
+          state.pending.insert(address, pending);
+        }
+      }
+      1 => {
+        match state.pending.remove(&address) {
+          Some(mut pending) if pending.id == id && pending.initiator => {
+            let mut payload = [0; MAX_HANDSHAKE_MESSAGE];
+            // Anything wrong with it and the handshake starts over with the next packet we send
+            if pending.state.read_message(&packet.payload, &mut payload).is_err() {
+              return outcome;
+            }
+            // -> s, se
+            let Some(message) = write_message(&mut pending.state, id, 2, &[]) else {
+              return outcome;
+            };
+            if let Some(peer) = finish(pending.state, id, Some(message.clone())) {
+              state.established.insert(address, peer);
+              outcome.reply = Some(message);
+              outcome.completed = true;
+            }
+          }
+          Some(pending) => {
+            state.pending.insert(address, pending);
+          }
+          None => {
+            // Our last message got lost, the peer is still waiting for it
+            if let Some(peer) = state.established.get(&address).filter(|peer| peer.handshake_id == id) {
+              outcome.reply = peer.last_message.clone();
+            }
+          }
+        }
+      }
+      2 => {
+        if let Some(mut pending) = state.pending.remove(&address) {
+          if pending.id != id || pending.initiator {
+            state.pending.insert(address, pending);
+            return outcome;
+          }
+
+          let mut payload = [0; MAX_HANDSHAKE_MESSAGE];
+          if pending.state.read_message(&packet.payload, &mut payload).is_err() {
+            return outcome;
+          }
+          if let Some(peer) = finish(pending.state, id, None) {
+            state.established.insert(address, peer);
+            outcome.completed = true;
+          }
+        }
+      }
+      _ => {}
+    }
+
+    outcome
+  }
+
+  // Handshake messages whose answer is overdue. Handshakes older than `handshake_timeout` are given up on.
+  pub fn retransmits(&self, config: &AckUdpConfig) -> Vec<(SocketAddr, Vec<u8>)> {
+    let mut state = self.state.lock();
+    state.pending.retain(|_, pending| pending.started_at.elapsed() < config.handshake_timeout);
+
+    let mut due = vec![];
+    for (address, pending) in state.pending.iter_mut() {
+      let rto = config.resend_interval.saturating_mul(1 << pending.attempts.min(16)).min(config.max_rto);
+      if pending.sent_at.elapsed() >= rto {
+        pending.sent_at = Instant::now();
+        pending.attempts += 1;
+        if pending.initiator || pending.spend() {
+          due.push((*address, pending.last_message.clone()));
+        }
+      }
+    }
+
+    due
+  }
+}
+
+impl PendingHandshake {
+  fn new(id: [u8; 5], state: HandshakeState, initiator: bool, last_message: Vec<u8>) -> Self {
+    PendingHandshake { id, state, initiator, last_message, budget: 0, started_at: Instant::now(), sent_at: Instant::now(), attempts: 0 }
+  }
+
+  // Takes the last message out of a responder's budget, `false` if it doesn't fit anymore
+  fn spend(&mut self) -> bool {
+    if self.last_message.len() > self.budget {
+      return false;
+    }
+    self.budget -= self.last_message.len();
+
+    true
+  }
+}
+
+fn make_room(pending: &mut HashMap<SocketAddr, PendingHandshake>) {
+  if pending.len() < MAX_PENDING_HANDSHAKES {
+    return;
+  }
+
+  let oldest = pending.iter()
+    .min_by_key(|(_, handshake)| handshake.started_at)
+    .map(|(address, _)| *address);
+  if let Some(address) = oldest {
+    pending.remove(&address);
+  }
+}
+
+fn noise_builder() -> Builder<'static> {
+  Builder::new(NOISE_PARAMS.parse().expect("valid noise params")).prologue(PROLOGUE)
+}
+
+fn write_message(handshake: &mut HandshakeState, id: [u8; 5], step: u32, payload: &[u8]) -> Option<Vec<u8>> {
+  // Room for a tag even where the payload isn't encrypted yet
+  let mut message = vec![0; MAX_HANDSHAKE_MESSAGE + payload.len()];
+  let length = handshake.write_message(payload, &mut message).ok()?;
+
+  Some(AckUdpPacket::new_handshake(id, step, &message[..length]))
+}
+
+// Packets are sealed with our own AEAD, Noise only agrees on the keys
+fn finish(mut handshake: HandshakeState, handshake_id: [u8; 5], last_message: Option<Vec<u8>>) -> Option<NoisePeer> {
+  let remote_key = to_key(handshake.get_remote_static()?);
+  let (initiator_key, responder_key) = handshake.dangerously_get_raw_split();
+  let cipher = if handshake.is_initiator() {
+    PacketCipher::from_keys(&initiator_key, &responder_key)
+  }
+  else {
+    PacketCipher::from_keys(&responder_key, &initiator_key)
+  };
+
+  Some(NoisePeer { cipher, remote_key, handshake_id, last_message })
+}
+
+fn to_key(bytes: &[u8]) -> [u8; 32] {
+  let mut key = [0; 32];
+  key.copy_from_slice(&bytes[..32]);
+
+  key
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use super::*;
+  use crate::types::PacketFlags;
+
+  fn address(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+  }
+
+  fn packet(raw_packet: &[u8]) -> AckUdpPacket {
+    AckUdpPacket::try_from(raw_packet).unwrap()
+  }
+
+  fn config(amplification_factor: usize) -> AckUdpConfig {
+    // Every pending handshake message is due whenever it's asked for
+    AckUdpConfig { amplification_factor, resend_interval: Duration::ZERO, ..AckUdpConfig::default() }
+  }
+
+  #[test]
+  fn handshake_agrees_on_keys() {
+    let config = config(1);
+    let (a_keypair, b_keypair) = (NoiseKeypair::generate(), NoiseKeypair::generate());
+    let (a, b) = (NoiseSessions::new(a_keypair.clone()), NoiseSessions::new(b_keypair.clone()));
+    let first = a.initiate(address(2)).unwrap();
+    assert!(a.initiate(address(2)).is_none());
+
+    let second = b.process(&config, address(1), &packet(&first)).reply.unwrap();
+    // Answering never sends more than was received
+    assert!(second.len() <= first.len());
+    let outcome = a.process(&config, address(2), &packet(&second));
+    assert!(outcome.completed);
+    assert!(b.process(&config, address(1), &packet(&outcome.reply.unwrap())).completed);
+
+    assert_eq!(a.remote_key(address(2)), Some(b_keypair.public));
+    assert_eq!(b.remote_key(address(1)), Some(a_keypair.public));
+    let mut segments = AckUdpPacket::split(b"secret", 100, [1; 5], 0, (0, 0), PacketFlags::empty());
+    let raw_packet = a.seal(address(2), segments.remove(&0).unwrap().into()).unwrap();
+    let mut sealed = packet(&raw_packet);
+    b.open(address(1), &mut sealed, &raw_packet).unwrap();
+    assert_eq!(sealed.payload, b"secret");
+  }
+
+  #[test]
+  fn responder_stays_within_its_budget() {
+    let a = NoiseSessions::new(NoiseKeypair::generate());
+    let first = a.initiate(address(2)).unwrap();
+
+    let b = NoiseSessions::new(NoiseKeypair::generate());
+    assert!(b.process(&config(1), address(1), &packet(&first)).reply.is_some());
+    assert!(b.retransmits(&config(1)).is_empty());
+
+    let b = NoiseSessions::new(NoiseKeypair::generate());
+    assert!(b.process(&config(3), address(1), &packet(&first)).reply.is_some());
+    assert_eq!(b.retransmits(&config(3)).len(), 1);
+    assert_eq!(b.retransmits(&config(3)).len(), 1);
+    assert!(b.retransmits(&config(3)).is_empty());
+    // The initiator resending its first message earns another answer
+    assert!(b.process(&config(3), address(1), &packet(&first)).reply.is_some());
+
+    // Without the padding the answer doesn't fit
+    let mut unpadded = packet(&first);
+    unpadded.payload.truncate(32);
+    unpadded.payload_size = 32;
+    let b = NoiseSessions::new(NoiseKeypair::generate());
+    assert!(b.process(&config(1), address(1), &unpadded).reply.is_none());
+  }
+
+  #[test]
+  fn pending_handshakes_are_capped() {
+    let a = NoiseSessions::new(NoiseKeypair::generate());
+    for port in 0..MAX_PENDING_HANDSHAKES as u16 + 10 {
+      assert!(a.initiate(address(port)).is_some());
+    }
+
+    assert_eq!(a.state.lock().pending.len(), MAX_PENDING_HANDSHAKES);
+  }
+}
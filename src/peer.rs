@@ -0,0 +1,210 @@
+use std::{collections::{HashMap, VecDeque}, net::SocketAddr, sync::Arc, time::Duration};
+
+use parking_lot::Mutex;
+use rand::Rng;
+use tokio::time::Instant;
+
+use crate::{congestion::CongestionController, types::sequence_after, AckUdpConfig};
+
+pub type AckUdpPeers = Arc<Mutex<HashMap<SocketAddr, AckUdpPeer>>>;
+
+// Unreliable datagram ids remembered per peer, so its cookie challenges about them are answered
+pub const RECENT_UNRELIABLE: usize = 64;
+
+// Everything we know about the remote side of a socket address
+#[derive(Debug)]
+pub struct AckUdpPeer {
+  pub rtt: RttEstimator,
+  pub congestion: Box<dyn CongestionController>,
+  pub bytes_in_flight: usize, // Sent to this peer, not ACKed and not declared lost yet
+  pub receive_window: usize, // Latest window advertised in this peer's ACKs
+  pub ordered: bool, // Reliable unordered channels to this peer are upgraded to ordered
+  pub next_sequences: HashMap<u8, (u32, u32, Instant)>, // By channel: next sequence number, its epoch and when the channel was last used
+  pub segment_size: usize, // Negotiated by a session handshake, our own `segment_size` otherwise
+  pub last_heard: Instant, // Last packet received from this peer
+  pub last_sent: Instant, // Last datagram queued for this peer
+  pub last_ping: Instant,
+  pub expecting_since: Option<Instant>, // Since when we wait for answers (pending datagrams or a session), silence before that doesn't count
+  pub validated: bool, // Proved it receives what we send to its address, so it isn't a spoofed source
+  pub cookie: Option<Vec<u8>>, // Latest cookie this peer challenged us with
+  pub recent_unreliable: VecDeque<[u8; 5]>, // Ids of the last unreliable datagrams sent to this peer, nothing else keeps them
+}
+
+impl AckUdpPeer {
+  pub fn new(config: &AckUdpConfig) -> Self {
+    AckUdpPeer {
+      rtt: RttEstimator::new(config.resend_interval, config.min_rto, config.max_rto, config.timer_granularity),
+      congestion: config.congestion_control.build(config),
+      bytes_in_flight: 0,
+      receive_window: config.receive_window,
+      ordered: false,
+      next_sequences: HashMap::new(),
+      segment_size: config.segment_size,
+      last_heard: Instant::now(),
+      last_sent: Instant::now(),
+      last_ping: Instant::now(),
+      expecting_since: None,
+      validated: false,
+      cookie: None,
+      recent_unreliable: VecDeque::new()
+    }
+  }
+
+  pub fn sent_unreliable(&mut self, datagram_id: [u8; 5]) {
+    if self.recent_unreliable.len() >= RECENT_UNRELIABLE {
+      self.recent_unreliable.pop_front();
+    }
+    self.recent_unreliable.push_back(datagram_id);
+  }
+
+  // Sequence number and epoch of the next datagram on `channel`. Starts over at 1 under a new epoch once the channel
+  // sat unused for half of `incoming_expiry` with nothing `in_flight` on it, well before the peer forgets its ordered
+  // inbox for us. Otherwise that inbox would be recreated waiting for 1.
+  pub fn next_sequence(&mut self, config: &AckUdpConfig, channel: u8, in_flight: bool) -> (u32, u32) {
+    let (next, epoch, last_used) = self.next_sequences.entry(channel).or_insert_with(|| (1, new_epoch(), Instant::now()));
+    if !in_flight && last_used.elapsed() >= config.incoming_expiry / 2 {
+      *next = 1;
+      *epoch = new_epoch();
+    }
+    *last_used = Instant::now();
+    let sequence = *next;
+    *next = sequence_after(sequence);
+
+    (sequence, *epoch)
+  }
+
+  // How long the peer didn't answer while we expected it to
+  pub fn silence(&self) -> Duration {
+    match self.expecting_since {
+      Some(expecting_since) => self.last_heard.max(expecting_since).elapsed(),
+      None => Duration::ZERO
+    }
+  }
+
+  // Nothing left we'd lose by forgetting the peer. Its sequence numbers start over afterwards,
+  // same as they do after `next_sequence` wasn't called for a while.
+  pub fn is_idle(&self, config: &AckUdpConfig) -> bool {
+    self.bytes_in_flight == 0
+      && !self.ordered
+      && self.last_heard.max(self.last_sent).elapsed() >= config.incoming_expiry * 2
+  }
+
+  // Flow control: the peer has to be able to buffer what is already in flight plus `bytes`.
+  // With nothing in flight one segment still goes out, its ACK tells us when the window reopens.
+  pub fn window_allows(&self, bytes: usize) -> bool {
+    self.bytes_in_flight == 0 || self.bytes_in_flight + bytes <= self.receive_window
+  }
+}
+
+// 0 is left for datagrams without a sequence number
+fn new_epoch() -> u32 {
+  rand::thread_rng().gen_range(1..=u32::MAX)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RttEstimate {
+  pub srtt: Duration, // Smoothed round trip time
+  pub rttvar: Duration, // Round trip time variation
+  pub rto: Duration, // Current retransmission timeout, before backoff
+}
+
+// SRTT / RTTVAR / RTO computation from RFC 6298
+#[derive(Debug, Clone)]
+pub struct RttEstimator {
+  estimate: Option<RttEstimate>,
+  initial_rto: Duration,
+  min_rto: Duration,
+  max_rto: Duration,
+  granularity: Duration,
+}
+
+impl RttEstimator {
+  pub fn new(initial_rto: Duration, min_rto: Duration, max_rto: Duration, granularity: Duration) -> Self {
+    RttEstimator { estimate: None, initial_rto, min_rto, max_rto, granularity }
+  }
+
+  // `None` until the first sample arrives
+  pub fn estimate(&self) -> Option<RttEstimate> {
+    self.estimate
+  }
+
+  pub fn sample(&mut self, rtt: Duration) {
+    let (srtt, rttvar) = match self.estimate {
+      None => (rtt, rtt / 2),
+      Some(RttEstimate { srtt, rttvar, .. }) => {
+        let deviation = srtt.abs_diff(rtt);
+        (srtt * 7 / 8 + rtt / 8, rttvar * 3 / 4 + deviation / 4)
+      }
+    };
+    let rto = (srtt + self.granularity.max(rttvar * 4)).clamp(self.min_rto, self.max_rto);
+
+    self.estimate = Some(RttEstimate { srtt, rttvar, rto });
+  }
+
+  // Timeout after `backoff` consecutive expirations, doubled on each of them
+  pub fn rto(&self, backoff: u16) -> Duration {
+    let rto = self.estimate.map(|v| v.rto).unwrap_or(self.initial_rto);
+
+    rto.saturating_mul(1 << backoff.min(16)).min(self.max_rto)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn estimator() -> RttEstimator {
+    RttEstimator::new(Duration::from_secs(1), Duration::from_millis(200), Duration::from_secs(10), Duration::from_millis(1))
+  }
+
+  #[test]
+  fn sequence_numbers_wrap_and_restart_when_unused() {
+    let config = AckUdpConfig::default();
+    let mut peer = AckUdpPeer::new(&config);
+    let (first, epoch) = peer.next_sequence(&config, 1, false);
+    assert_eq!(first, 1);
+    assert_eq!(peer.next_sequence(&config, 1, false), (2, epoch));
+    assert_eq!(peer.next_sequence(&config, 2, false).0, 1);
+
+    // Wrapping around is no restart, the epoch stays
+    peer.next_sequences.insert(1, (u32::MAX, epoch, Instant::now()));
+    assert_eq!(peer.next_sequence(&config, 1, false), (u32::MAX, epoch));
+    assert_eq!(peer.next_sequence(&config, 1, false), (1, epoch));
+
+    // A channel left unused starts over under a new epoch, unless something on it may still arrive
+    let config = AckUdpConfig { incoming_expiry: Duration::ZERO, ..AckUdpConfig::default() };
+    assert_eq!(peer.next_sequence(&config, 1, true), (2, epoch));
+    let (restarted, new_epoch) = peer.next_sequence(&config, 1, false);
+    assert!(restarted == 1 && new_epoch != epoch && new_epoch != 0);
+  }
+
+  #[test]
+  fn first_sample_sets_the_estimate() {
+    let mut rtt = estimator();
+    assert_eq!(rtt.rto(0), Duration::from_secs(1));
+
+    rtt.sample(Duration::from_millis(100));
+    let estimate = rtt.estimate().unwrap();
+    assert_eq!((estimate.srtt, estimate.rttvar), (Duration::from_millis(100), Duration::from_millis(50)));
+    assert_eq!(estimate.rto, Duration::from_millis(300));
+  }
+
+  #[test]
+  fn later_samples_are_smoothed() {
+    let mut rtt = estimator();
+    rtt.sample(Duration::from_millis(100));
+    rtt.sample(Duration::from_millis(180));
+    let estimate = rtt.estimate().unwrap();
+    assert_eq!(estimate.srtt, Duration::from_millis(110));
+    assert_eq!(estimate.rttvar, Duration::from_micros(57_500));
+  }
+
+  #[test]
+  fn rto_is_clamped_and_backs_off() {
+    let mut rtt = estimator();
+    rtt.sample(Duration::from_millis(10));
+    assert_eq!(rtt.rto(0), Duration::from_millis(200));
+    assert_eq!(rtt.rto(2), Duration::from_millis(800));
+    assert_eq!(rtt.rto(u16::MAX), Duration::from_secs(10));
+  }
+}
@@ -0,0 +1,191 @@
+use std::{collections::{HashMap, VecDeque}, io, net::SocketAddr, sync::Arc};
+
+use parking_lot::Mutex;
+use tokio::{sync::Notify, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+  types::{AckUdpPacket, DeliveryHandle, PacketFlags},
+  sock_send::SockSend,
+  peer::AckUdpPeer,
+  noise::PublicKey,
+  shared::AckUdpShared,
+  AckUdp
+};
+
+pub type AckUdpSessions = Arc<Mutex<HashMap<SocketAddr, AckUdpSessionState>>>; // At most one session per peer
+pub type AckUdpAcceptQueue = Arc<Mutex<VecDeque<(SocketAddr, [u8; 5])>>>; // Established sessions waiting for `accept`
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionStatus {
+  Connecting, // Our CONNECT isn't answered yet
+  Established,
+  Closing, // Our CLOSE isn't answered yet
+}
+
+#[derive(Debug)]
+pub struct AckUdpSessionState {
+  pub id: [u8; 5], // Picked by the connecting side
+  pub status: SessionStatus,
+  pub segment_size: usize, // Smaller one of both sides
+  pub closed: CancellationToken, // Cancelled once either side closes the session or the socket is shut down
+  pub changed: Arc<Notify>, // Woken on every status change
+}
+
+impl AckUdpSessionState {
+  pub fn new(id: [u8; 5], status: SessionStatus, segment_size: usize, closed: CancellationToken) -> Self {
+    AckUdpSessionState { id, status, segment_size, closed, changed: Arc::new(Notify::new()) }
+  }
+
+  // Lets whoever waits on the session know it's over
+  pub fn close(&self) {
+    self.closed.cancel();
+    self.changed.notify_waiters();
+  }
+}
+
+// Connection to one peer, made by `AckUdp::connect` or `AckUdp::accept`. Datagrams from its peer are read
+// here instead of `AckUdp::recv` for as long as the session lasts.
+pub struct AckUdpSession {
+  pub id: [u8; 5],
+  pub address: SocketAddr,
+  pub segment_size: usize, // Negotiated in the handshake
+  pub remote_key: Option<PublicKey>, // Peer's static key if Noise is on
+
+  closed: CancellationToken,
+  shared: Arc<AckUdpShared>,
+}
+
+impl AckUdpSession {
+  pub(crate) fn new(shared: &Arc<AckUdpShared>, address: SocketAddr, id: [u8; 5], segment_size: usize, closed: CancellationToken) -> Self {
+    AckUdpSession {
+      id,
+      address,
+      segment_size,
+      remote_key: shared.sock.sealing.remote_key(address),
+      closed,
+      shared: shared.clone(),
+    }
+  }
+
+  // Reliable send on channel 0
+  pub fn send(&self, buf: &[u8]) -> io::Result<DeliveryHandle> {
+    self.send_on(0, buf).map(|delivery| delivery.expect("channel 0 is reliable"))
+  }
+
+  pub fn send_on(&self, channel: u8, buf: &[u8]) -> io::Result<Option<DeliveryHandle>> {
+    self.check_open()?;
+
+    Ok(AckUdp::send_datagram(&self.shared, channel, buf, self.address))
+  }
+
+  pub fn send_unreliable(&self, buf: &[u8]) -> io::Result<()> {
+    self.check_open()?;
+
+    AckUdp::send_unreliable_datagram(&self.shared, buf, self.address);
+
+    Ok(())
+  }
+
+  // `None` once the session is closed and everything its peer sent before is read
+  pub async fn recv(&self) -> Option<Vec<u8>> {
+    self.recv_from_channel(None).await
+  }
+
+  pub async fn recv_on(&self, channel: u8) -> Option<Vec<u8>> {
+    self.recv_from_channel(Some(channel)).await
+  }
+
+  pub fn try_recv(&self) -> Option<Vec<u8>> {
+    AckUdp::take_ready(&self.shared, None, |address| self.owns(address))
+      .map(|(_, _, buf)| buf)
+  }
+
+  async fn recv_from_channel(&self, channel: Option<u8>) -> Option<Vec<u8>> {
+    AckUdp::wait_ready(
+      &self.shared,
+      &self.closed,
+      channel,
+      |address| self.owns(address)
+    ).await.map(|(_, _, buf)| buf)
+  }
+
+  // A newer session with the same peer takes over whatever is left
+  fn owns(&self, address: SocketAddr) -> bool {
+    address == self.address
+      && (!self.closed.is_cancelled() || self.shared.sessions.lock().get(&address).is_none_or(|state| state.id == self.id))
+  }
+
+  pub fn is_closed(&self) -> bool {
+    self.closed.is_cancelled()
+  }
+
+  // Resolves once either side closes the session or the socket is shut down
+  pub async fn closed(&self) {
+    self.closed.cancelled().await
+  }
+
+  // Tells the peer the session is over and waits (up to `handshake_timeout`) for it to confirm.
+  // Datagrams already sent keep being retransmitted.
+  pub async fn close(&self) {
+    let changed = {
+      let mut sessions = self.shared.sessions.lock();
+      match sessions.get_mut(&self.address).filter(|state| state.id == self.id) {
+        Some(state) if !self.closed.is_cancelled() => {
+          state.status = SessionStatus::Closing;
+          state.close();
+          state.changed.clone()
+        }
+        _ => return
+      }
+    };
+
+    let deadline = Instant::now() + self.shared.config.handshake_timeout;
+    let mut attempt = 0;
+    loop {
+      let notified = changed.notified();
+      // Removed once the peer answers
+      if self.shared.sessions.lock().get(&self.address).is_none_or(|state| state.id != self.id) {
+        return;
+      }
+      if Instant::now() >= deadline {
+        break;
+      }
+
+      self.shared.sock.sock_send(AckUdpPacket::new_close(self.id, PacketFlags::CLOSE), self.address);
+      let rto = self.shared.peers.lock().entry(self.address).or_insert_with(|| AckUdpPeer::new(&self.shared.config)).rtt.rto(attempt);
+      attempt = attempt.saturating_add(1);
+
+      tokio::select! {
+        _ = notified => {},
+        _ = tokio::time::sleep_until((Instant::now() + rto).min(deadline)) => {}
+      }
+    }
+
+    let mut sessions = self.shared.sessions.lock();
+    if sessions.get(&self.address).is_some_and(|state| state.id == self.id) {
+      sessions.remove(&self.address);
+    }
+  }
+
+  fn check_open(&self) -> io::Result<()> {
+    if self.closed.is_cancelled() {
+      return Err(io::Error::new(io::ErrorKind::NotConnected, "session is closed"));
+    }
+
+    Ok(())
+  }
+}
+
+impl Drop for AckUdpSession {
+  // Best effort, a lost CLOSE leaves the peer's session open until it closes it itself
+  fn drop(&mut self) {
+    let mut sessions = self.shared.sessions.lock();
+    if sessions.get(&self.address).is_some_and(|state| state.id == self.id) {
+      if let Some(state) = sessions.remove(&self.address) {
+        state.close();
+      }
+      self.shared.sock.sock_send(AckUdpPacket::new_close(self.id, PacketFlags::CLOSE), self.address);
+    }
+  }
+}
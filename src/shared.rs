@@ -0,0 +1,73 @@
+use std::{
+  sync::{atomic::AtomicUsize, Arc},
+  collections::{HashMap, VecDeque}
+};
+use tokio::sync::{broadcast, Notify};
+use tokio_util::sync::CancellationToken;
+
+use parking_lot::Mutex;
+
+use crate::{
+  types::{AckUdpDatagrams, AckUdpStatusLinks, AckUdpQueue, AckUdpDeliveredWindows, AckUdpOrderedInboxes, AckUdpBufferedBytes, AckUdpStats, AckUdpEvent, ReadyQueue},
+  sock_send::AckUdpSocket,
+  peer::AckUdpPeers,
+  session::{AckUdpSessions, AckUdpAcceptQueue},
+  cookie::CookieJar,
+  filter::SourceFilter,
+  AckUdpConfig
+};
+
+// State of one socket. The background tasks, `AckUdp` and its sessions all hold the same one.
+pub(crate) struct AckUdpShared {
+  pub sock: Arc<AckUdpSocket>,
+  pub config: Arc<AckUdpConfig>,
+  pub stats: Arc<AckUdpStats>,
+  pub cookies: Arc<CookieJar>,
+  pub filter: Arc<SourceFilter>,
+
+  pub ready_to_read_datagrams: AckUdpQueue,
+  pub ready_to_read_notify: Arc<Notify>,
+  pub buffered_in_bytes: AckUdpBufferedBytes,
+  pub pending_in_datagrams: AckUdpDatagrams,
+  pub delivered_datagrams: AckUdpDeliveredWindows,
+  pub ordered_inboxes: AckUdpOrderedInboxes,
+  pub pending_out_datagrams: AckUdpDatagrams,
+
+  pub out_datagrams_status_links: AckUdpStatusLinks,
+  pub peers: AckUdpPeers,
+
+  pub sessions: AckUdpSessions,
+  pub accept_queue: AckUdpAcceptQueue,
+  pub accept_notify: Arc<Notify>,
+
+  pub events: broadcast::Sender<AckUdpEvent>,
+
+  // Cancelled on `shutdown` or drop, stops every background task
+  pub cancellation_token: CancellationToken,
+}
+
+impl AckUdpShared {
+  pub fn new(sock: AckUdpSocket, config: AckUdpConfig) -> Self {
+    AckUdpShared {
+      sock: Arc::new(sock),
+      config: Arc::new(config),
+      stats: Arc::new(AckUdpStats::default()),
+      cookies: Arc::new(CookieJar::default()),
+      filter: Arc::new(SourceFilter::default()),
+      ready_to_read_datagrams: Arc::new(Mutex::new(ReadyQueue::default())),
+      ready_to_read_notify: Arc::new(Notify::new()),
+      buffered_in_bytes: Arc::new(AtomicUsize::new(0)),
+      pending_in_datagrams: Arc::new(Mutex::new(HashMap::new())),
+      delivered_datagrams: Arc::new(Mutex::new(HashMap::new())),
+      ordered_inboxes: Arc::new(Mutex::new(HashMap::new())),
+      pending_out_datagrams: Arc::new(Mutex::new(HashMap::new())),
+      out_datagrams_status_links: Arc::new(Mutex::new(HashMap::new())),
+      peers: Arc::new(Mutex::new(HashMap::new())),
+      sessions: Arc::new(Mutex::new(HashMap::new())),
+      accept_queue: Arc::new(Mutex::new(VecDeque::new())),
+      accept_notify: Arc::new(Notify::new()),
+      events: broadcast::channel(64).0,
+      cancellation_token: CancellationToken::new(),
+    }
+  }
+}
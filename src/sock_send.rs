@@ -1,22 +1,100 @@
 use std::sync::Arc;
-use std::{net::SocketAddr, io};
+use std::{net::SocketAddr, io, ops::Deref};
 use tokio::net::UdpSocket;
 use tokio::task;
 
+use crate::{crypto::PacketCipher, noise::{NoiseSessions, PublicKey}, types::{AckUdpPacket, PacketFlags}, AckUdpError};
+
 pub trait SockSend {
   fn sock_send(&self, buf: Vec<u8>, address: SocketAddr);
+  // Bypasses sealing, only for Noise handshake messages
+  fn sock_send_plain(&self, buf: Vec<u8>, address: SocketAddr);
+}
+
+// How packets are protected on the wire
+pub enum Sealing {
+  Plain,
+  Psk(PacketCipher), // One key for every peer
+  Noise(NoiseSessions), // Keys per peer, agreed on in a handshake
 }
 
-async fn send(socket: Arc<UdpSocket>, buf: Vec<u8>, address: SocketAddr) -> io::Result<usize> {
+impl Sealing {
+  // With a key nothing unsealed gets through, without one nothing sealed can be read.
+  // Noise handshake messages are the exception, they're read by `process_packets`.
+  pub fn open(&self, address: SocketAddr, packet: &mut AckUdpPacket, raw_packet: &[u8]) -> Result<(), AckUdpError> {
+    match self {
+      Sealing::Plain if packet.flags.contains(PacketFlags::ENCRYPTED) => Err(AckUdpError::Unauthenticated),
+      Sealing::Plain => Ok(()),
+      Sealing::Psk(cipher) => cipher.open(packet, raw_packet),
+      Sealing::Noise(_) if packet.flags.contains(PacketFlags::HANDSHAKE) => Ok(()),
+      Sealing::Noise(noise) => noise.open(address, packet, raw_packet),
+    }
+  }
+
+  // Static key of the peer, `None` unless Noise is on and the handshake with it is done
+  pub fn remote_key(&self, address: SocketAddr) -> Option<PublicKey> {
+    match self {
+      Sealing::Noise(noise) => noise.remote_key(address),
+      _ => None
+    }
+  }
+}
+
+// UDP socket that seals everything sent through `sock_send` once a pre-shared key or Noise is configured
+pub struct AckUdpSocket {
+  socket: UdpSocket,
+  pub sealing: Sealing,
+}
+
+impl AckUdpSocket {
+  pub fn new(socket: UdpSocket, sealing: Sealing) -> Self {
+    AckUdpSocket { socket, sealing }
+  }
+}
+
+impl Deref for AckUdpSocket {
+  type Target = UdpSocket;
+
+  fn deref(&self) -> &UdpSocket {
+    &self.socket
+  }
+}
+
+async fn send(socket: Arc<AckUdpSocket>, buf: Vec<u8>, address: SocketAddr) -> io::Result<usize> {
   let a = socket.send_to(&buf, address).await?;
 
   Ok(a)
 }
 
-impl SockSend for Arc<UdpSocket> {
+impl SockSend for Arc<AckUdpSocket> {
   fn sock_send(&self, buf: Vec<u8>, address: SocketAddr) {
-    let socket = self.clone();
+    let buf = match &self.sealing {
+      Sealing::Plain => buf,
+      Sealing::Psk(cipher) => cipher.seal(buf),
+      Sealing::Noise(noise) => match noise.seal(address, buf) {
+        Some(buf) => buf,
+        // Nothing leaves unsealed. Reliable packets are resent once the handshake is done, unreliable ones are lost.
+        None => {
+          if let Some(message) = noise.initiate(address) {
+            self.sock_send_plain(message, address);
+          }
+          return;
+        }
+      }
+    };
 
-    task::spawn(send(socket, buf, address));
+    self.sock_send_plain(buf, address);
   }
-}
\ No newline at end of file
+
+  fn sock_send_plain(&self, buf: Vec<u8>, address: SocketAddr) {
+    // Send right away when the socket is writable so packets leave in the order they were produced,
+    // a task per packet may run in any order and look like loss to the receiver
+    if let Err(e) = self.try_send_to(&buf, address) {
+      if e.kind() == io::ErrorKind::WouldBlock {
+        let socket = self.clone();
+
+        task::spawn(send(socket, buf, address));
+      }
+    }
+  }
+}
@@ -1,66 +1,838 @@
 // AckUdp Packet Header
-// 5 bytes datagram id   4 bytes segment index   4 bytes total segments number   1 byte ACK   2 bytes payload size
-// -------------------___---------------------___-----------------------------___----------___--------------------
+// 2 bytes magic   1 byte version   4 bytes checksum   2 bytes flags   5 bytes datagram id   4 bytes segment index   4 bytes total segments number   4 bytes sequence   4 bytes epoch   1 byte channel   2 bytes payload size
+// -------------___--------------___---------------___------------___-------------------___---------------------___-----------------------------___----------------___-------------___--------------___--------------------
+// magic: "AU", anything else isn't ours
+// checksum: CRC32C of the whole packet except the checksum itself
+// flags: see `PacketFlags`, none set - reliable data
+// sequence: per peer and channel number of an ordered or sequenced datagram, 0 for the others
+// epoch: random id of the sender's current run of sequence numbers on the channel, a new one each time they start over at 1
+// ACK payload: 4 bytes receive window (bytes the receiver can still buffer)   4 bytes cumulative ACK (every segment
+// below this index is received)   then 8 bytes per range of segments received above it (4 bytes start, 4 bytes end, exclusive)
+// NACK payload: 4 bytes per missing segment index
+// CONNECT payload: 2 bytes segment size of the side sending it. The datagram id carries the session id, same for CLOSE
+// PING: no payload, answered with PING | ACK
+// HANDSHAKE payload: one Noise handshake message, the segment index tells which one. The datagram id carries the handshake id
+// COOKIE payload: a cookie (see `CookieJar`) the sender has to echo back in a COOKIE | ACK. The datagram id is the one of the refused datagram
+// REJECTED payload: 1 byte `RejectReason`. The datagram id is the one of the refused datagram
+// ENCRYPTED payload: 16 bytes nonce (8 bytes sender id, 8 bytes counter)   then the sealed payload and its 16 bytes tag. The header is authenticated with it
 
-use std::{collections::{HashMap, HashSet}, io::Cursor, net::SocketAddr, sync::Arc};
-use chrono::prelude::*;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::{
+  collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque}, 
+  error::Error, 
+  fmt, 
+  future::Future, 
+  io::{Cursor, Read}, 
+  net::{IpAddr, SocketAddr}, 
+  ops::BitOr, 
+  pin::Pin, 
+  sync::{atomic::{AtomicU64, AtomicUsize}, Arc}, 
+  task::{Context, Poll, Waker}, 
+  time::Duration
+};
+use tokio::time::Instant;
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
 use parking_lot::Mutex;
 
+use crate::{error::AckUdpError, noise::PublicKey};
+
+pub const HEADER_SIZE: usize = 33;
+// Smallest segment size, enough for an ACK with one SACK range
+pub const MIN_SEGMENT_SIZE: usize = 16;
+// Memory a held segment takes besides its payload, charged to `receive_window` so tiny segments can't pile up for free
+pub const SEGMENT_OVERHEAD: usize = 64;
+// Later segments of a datagram that have to arrive before a missing one is NACKed, so reordering alone triggers no resends
+pub const NACK_THRESHOLD: usize = 3;
+pub const MAGIC: [u8; 2] = *b"AU";
+pub const PROTOCOL_VERSION: u8 = 2;
+
+pub type AckUdpDatagrams = Arc<Mutex<HashMap<[u8; 5], AckUdpDatagram>>>;
+pub type AckUdpStatusLinks = Arc<Mutex<HashMap<[u8; 5], Arc<Mutex<AckUdpDatagramOutStatus>>>>>;
+pub type AckUdpQueue = Arc<Mutex<ReadyQueue>>;
+pub type IncomingPacket = (SocketAddr, AckUdpPacket);
+// INcome payload bytes held in pending and ready to read datagrams, counted against `receive_window`
+pub type AckUdpBufferedBytes = Arc<AtomicUsize>;
+
+pub type AckUdpDeliveredWindows = Arc<Mutex<HashMap<SocketAddr, DeliveredWindow>>>;
+pub type AckUdpOrderedInboxes = Arc<Mutex<HashMap<(SocketAddr, u8), OrderedInbox>>>; // By source and channel
+
+// Recently delivered datagram ids of one peer. Lets retransmits be ACKed again without
+// handing the same datagram to the application twice.
+#[derive(Debug, Default)]
+pub struct DeliveredWindow {
+  order: VecDeque<([u8; 5], Instant)>,
+  ids: HashSet<[u8; 5]>,
+}
+
+impl DeliveredWindow {
+  pub fn contains(&self, id: &[u8; 5]) -> bool {
+    self.ids.contains(id)
+  }
+
+  pub fn insert(&mut self, id: [u8; 5], size: usize) {
+    if self.ids.insert(id) {
+      self.order.push_back((id, Instant::now()));
+    }
+
+    while self.order.len() > size {
+      if let Some((old_id, _)) = self.order.pop_front() {
+        self.ids.remove(&old_id);
+      }
+    }
+  }
+
+  pub fn expire(&mut self, ttl: Duration) {
+    while let Some((id, delivered_at)) = self.order.front() {
+      if delivered_at.elapsed() < ttl {
+        break;
+      }
+
+      self.ids.remove(id);
+      self.order.pop_front();
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.order.is_empty()
+  }
+}
+
+// Reassembled datagrams waiting for `recv`, queued per channel so reading one channel never scans past what's
+// waiting on the others. Each one keeps its arrival number for readers of every channel to take the oldest.
+#[derive(Debug, Default)]
+pub struct ReadyQueue {
+  arrivals: u64,
+  channels: HashMap<u8, VecDeque<ReadyDatagram>>,
+}
+
+type ReadyDatagram = (u64, SocketAddr, Option<PublicKey>, Vec<u8>); // Arrival, source, its Noise static key, payload
+
+impl ReadyQueue {
+  pub fn push(&mut self, address: SocketAddr, channel: u8, remote_key: Option<PublicKey>, payload: Vec<u8>) {
+    self.arrivals += 1;
+    self.channels.entry(channel).or_default().push_back((self.arrivals, address, remote_key, payload));
+  }
+
+  // Oldest datagram on `channel`, or on any channel if it's `None`, whose source `filter` accepts
+  pub fn take(&mut self, channel: Option<u8>, filter: impl Fn(SocketAddr) -> bool) -> Option<(SocketAddr, Option<PublicKey>, Vec<u8>)> {
+    let (channel, position) = self.channels.iter()
+      .filter(|(queue_channel, _)| channel.is_none_or(|channel| channel == **queue_channel))
+      .filter_map(|(queue_channel, queue)| {
+        let position = queue.iter().position(|(_, address, _, _)| filter(*address))?;
+        Some((queue[position].0, *queue_channel, position))
+      })
+      .min()
+      .map(|(_, channel, position)| (channel, position))?;
+
+    let queue = self.channels.get_mut(&channel)?;
+    let (_, address, remote_key, payload) = queue.remove(position)?;
+    if queue.is_empty() {
+      self.channels.remove(&channel);
+    }
+
+    Some((address, remote_key, payload))
+  }
+}
+
+// Sequence numbers run from 1 to `u32::MAX` and wrap around to 1, 0 marks datagrams without one
+pub fn sequence_after(sequence: u32) -> u32 {
+  sequence.checked_add(1).unwrap_or(1)
+}
+
+// Serial number comparison (RFC 1982): `a` is older than `b` if it's less than half the sequence space behind it
+pub fn sequence_before(a: u32, b: u32) -> bool {
+  (b.wrapping_sub(a) as i32) > 0
+}
+
+// Earlier runs of sequence numbers an ordered inbox remembers, so their stragglers aren't taken for a new run
+const RETIRED_EPOCHS: usize = 8;
+// How far ahead of the next expected sequence number a DROPPED is taken into account. Further ones are ignored
+// and left to `incoming_expiry`, so forged ones can't pile up.
+pub const MAX_SKIP_AHEAD: u32 = 1024;
+
+// Sequenced datagrams of one peer's channel. Ordered ones that got reassembled ahead of an earlier
+// sequence number are held back here.
 #[derive(Debug)]
+pub struct OrderedInbox {
+  epoch: u32, // Sender's current run of sequence numbers
+  retired_epochs: VecDeque<u32>, // Its earlier runs, whatever still arrives for them is late
+  next_sequence: u32,
+  held: BTreeMap<u32, Vec<u8>>,
+  dropped: BTreeSet<u32>, // Sequence numbers the sender gave up on, skipped once reached
+  stalled_since: Instant, // Since when the first held datagram waits for earlier ones
+  last_active: Instant, // Last datagram or DROPPED from the sender
+}
+
+impl OrderedInbox {
+  pub fn new(epoch: u32) -> Self {
+    OrderedInbox { 
+      epoch,
+      retired_epochs: VecDeque::new(),
+      next_sequence: 1, 
+      held: BTreeMap::new(), 
+      dropped: BTreeSet::new(), 
+      stalled_since: Instant::now(), 
+      last_active: Instant::now() 
+    }
+  }
+
+  // Sequence numbers start over with a new epoch when the sender restarts. Whatever is held for the previous run
+  // was ACKed and nothing earlier is coming anymore, so it's returned to be read before the new run.
+  // `None` if `epoch` is one of the earlier runs.
+  pub fn restart(&mut self, epoch: u32) -> Option<Vec<Vec<u8>>> {
+    if epoch == self.epoch {
+      return Some(vec![]);
+    }
+    if self.retired_epochs.contains(&epoch) {
+      return None;
+    }
+
+    let mut held: Vec<(u32, Vec<u8>)> = std::mem::take(&mut self.held).into_iter().collect();
+    let next_sequence = self.next_sequence;
+    held.sort_by_key(|(sequence, _)| sequence.wrapping_sub(next_sequence));
+
+    if self.retired_epochs.len() >= RETIRED_EPOCHS {
+      self.retired_epochs.pop_front();
+    }
+    self.retired_epochs.push_back(self.epoch);
+    self.epoch = epoch;
+    self.next_sequence = 1;
+    self.dropped.clear();
+    self.stalled_since = Instant::now();
+
+    Some(held.into_iter().map(|(_, payload)| payload).collect())
+  }
+
+  // Payloads that can be read now, in order. `None` if `sequence` was already given up on.
+  pub fn push(&mut self, sequence: u32, payload: Vec<u8>) -> Option<Vec<Vec<u8>>> {
+    self.last_active = Instant::now();
+    if sequence_before(sequence, self.next_sequence) {
+      return None;
+    }
+
+    if self.held.is_empty() {
+      self.stalled_since = Instant::now();
+    }
+    self.held.insert(sequence, payload);
+
+    Some(self.release())
+  }
+
+  // Unreliable sequenced datagrams: only ones newer than everything read so far are worth reading
+  pub fn accept_newer(&mut self, sequence: u32) -> bool {
+    self.last_active = Instant::now();
+    if sequence_before(sequence, self.next_sequence) {
+      return false;
+    }
+
+    self.next_sequence = sequence_after(sequence);
+    true
+  }
+
+  // The sender gave up on `sequence` of run `epoch`. Returns what can be read now.
+  pub fn skip(&mut self, epoch: u32, sequence: u32) -> Vec<Vec<u8>> {
+    let Some(mut released) = self.restart(epoch) else {
+      return vec![];
+    };
+
+    self.last_active = Instant::now();
+    let is_near = sequence.wrapping_sub(self.next_sequence) < MAX_SKIP_AHEAD;
+    if is_near && self.dropped.len() < MAX_SKIP_AHEAD as usize && !self.held.contains_key(&sequence) {
+      self.dropped.insert(sequence);
+    }
+    released.extend(self.release());
+
+    released
+  }
+
+  // Stops waiting for missing sequence numbers once nothing moved for `expiry`
+  pub fn expire(&mut self, expiry: Duration) -> Vec<Vec<u8>> {
+    // Held ones may have wrapped around past `u32::MAX`, the first is the closest to `next_sequence`
+    let first = self.held.keys().min_by_key(|sequence| sequence.wrapping_sub(self.next_sequence));
+    match first {
+      Some(&first) if self.stalled_since.elapsed() >= expiry => {
+        self.next_sequence = first;
+        self.release()
+      }
+      _ => vec![]
+    }
+  }
+
+  // Nothing held and nothing heard from the sender for `expiry`. Senders start their sequence numbers over
+  // well before that, so the inbox can go.
+  pub fn is_idle(&self, expiry: Duration) -> bool {
+    self.held.is_empty() && self.last_active.elapsed() >= expiry
+  }
+
+  fn release(&mut self) -> Vec<Vec<u8>> {
+    let mut released = vec![];
+    loop {
+      if let Some(payload) = self.held.remove(&self.next_sequence) {
+        released.push(payload);
+      }
+      else if !self.dropped.remove(&self.next_sequence) {
+        break;
+      }
+
+      self.next_sequence = sequence_after(self.next_sequence);
+      self.stalled_since = Instant::now();
+    }
+    let next_sequence = self.next_sequence;
+    self.dropped.retain(|sequence| !sequence_before(*sequence, next_sequence));
+
+    released
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct AckUdpStats {
+  pub malformed_packets: AtomicU64, // Packets that failed to decode and were discarded
+  pub incompatible_packets: AtomicU64, // Foreign traffic or packets of another protocol version, discarded
+  pub corrupted_packets: AtomicU64, // Packets whose checksum didn't match, discarded so the sender retransmits them
+  pub unauthenticated_packets: AtomicU64, // Packets not sealed with our pre-shared key (or sealed while we have none), discarded
+  pub replayed_packets: AtomicU64, // Sealed packets opened before, or our own ones sent back to us, discarded
+  pub io_errors: AtomicU64, // Errors returned by the socket while receiving
+  pub cookie_challenges: AtomicU64, // Segments from unvalidated addresses answered with a cookie instead of being reassembled
+  pub rejected_datagrams: AtomicU64, // INcome datagrams refused for exceeding `max_datagram_size` or `max_pending_in_per_peer`, or for odd segment sizes
+  pub evicted_datagrams: AtomicU64, // Incomplete INcome datagrams dropped to make room once `max_pending_in` was reached
+  pub filtered_packets: AtomicU64, // Packets from denied, banned or not allowed IPs, discarded before decoding
+  pub rate_limited_packets: AtomicU64, // Packets over the `rate_limit` of their IP, discarded before decoding
+  pub overloaded_packets: AtomicU64, // Packets discarded because `incoming_queue_capacity` of them were already waiting to be processed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AckUdpDatagramOutStatusEnum {
   Pending,
   Dropped,
   Succeeded
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryReport {
+  pub datagram_id: [u8; 5],
+  pub address: SocketAddr,
+  pub segments_count: u32,
+  pub elapsed: Duration, // Time from `send` until the last segment was ACKed
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SendError {
+  Dropped { attempts: u16 }, // Retry limit reached without the full ACK
+  Shutdown, // Socket was shut down before the datagram was ACKed
+  PeerDead, // Nothing was heard from the peer for `peer_timeout`
+  Rejected(RejectReason), // The peer refused to reassemble the datagram
+}
+
+impl fmt::Display for SendError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      SendError::Dropped { attempts } => write!(f, "datagram dropped after {attempts} resend attempts"),
+      SendError::Shutdown => write!(f, "socket was shut down before the datagram was delivered"),
+      SendError::PeerDead => write!(f, "peer stopped answering before the datagram was delivered"),
+      SendError::Rejected(reason) => write!(f, "peer rejected the datagram: {reason}"),
+    }
+  }
+}
+
+impl Error for SendError {}
+
+// Why a receiver refused a datagram, see the reassembly limits in `AckUdpConfig`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+  TooBig = 1, // Bigger than `max_datagram_size`
+  TooManyPending = 2, // The sender already has `max_pending_in_per_peer` datagrams being reassembled
+  Evicted = 3, // Dropped halfway to make room for newer datagrams once `max_pending_in` was reached
+  Late = 4, // Ordered, and arrived after the receiver stopped waiting for it and read later ones
+  Malformed = 5, // A segment other than the last one is smaller than any segment size
+  Unknown = 0, // Sent by a newer version
+}
+
+impl From<u8> for RejectReason {
+  fn from(code: u8) -> Self {
+    match code {
+      1 => RejectReason::TooBig,
+      2 => RejectReason::TooManyPending,
+      3 => RejectReason::Evicted,
+      4 => RejectReason::Late,
+      5 => RejectReason::Malformed,
+      _ => RejectReason::Unknown
+    }
+  }
+}
+
+impl fmt::Display for RejectReason {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      RejectReason::TooBig => write!(f, "datagram too big"),
+      RejectReason::TooManyPending => write!(f, "too many datagrams pending reassembly"),
+      RejectReason::Evicted => write!(f, "evicted to make room for newer datagrams"),
+      RejectReason::Late => write!(f, "arrived after later datagrams were read"),
+      RejectReason::Malformed => write!(f, "segment smaller than the segment size"),
+      RejectReason::Unknown => write!(f, "unknown reason"),
+    }
+  }
+}
+
+// Sent to every `AckUdp::subscribe_events` receiver
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckUdpEvent {
+  // Nothing was heard from a peer we expect answers from (pending datagrams or a session) for `peer_timeout`.
+  // Its pending datagrams fail with `SendError::PeerDead` and its session is closed.
+  PeerDead(SocketAddr),
+  // An IP went over `ban_threshold`, everything from it is discarded for `ban_duration`
+  PeerBanned(IpAddr),
+}
+
 #[derive(Debug)]
-pub struct AckUdpDatagramOutStatus(pub AckUdpDatagramOutStatusEnum);
+pub struct AckUdpDatagramOutStatus {
+  pub status: AckUdpDatagramOutStatusEnum,
+  pub datagram_id: [u8; 5],
+  pub address: SocketAddr,
+  pub segments_count: u32,
+  pub sent_at: Instant,
+
+  result: Option<Result<DeliveryReport, SendError>>,
+  wakers: Vec<Waker>,
+}
+
+impl AckUdpDatagramOutStatus {
+  pub fn new(datagram_id: [u8; 5], address: SocketAddr, segments_count: u32) -> Self {
+    AckUdpDatagramOutStatus { 
+      status: AckUdpDatagramOutStatusEnum::Pending, 
+      datagram_id, 
+      address, 
+      segments_count, 
+      sent_at: Instant::now(), 
+      result: None, 
+      wakers: vec![] 
+    }
+  }
+
+  pub fn succeed(&mut self) {
+    let elapsed = self.sent_at.elapsed();
+    let report = DeliveryReport { 
+      datagram_id: self.datagram_id, 
+      address: self.address, 
+      segments_count: self.segments_count, 
+      elapsed 
+    };
+
+    self.status = AckUdpDatagramOutStatusEnum::Succeeded;
+    self.resolve(Ok(report));
+  }
+
+  pub fn fail(&mut self, error: SendError) {
+    self.status = AckUdpDatagramOutStatusEnum::Dropped;
+    self.resolve(Err(error));
+  }
+
+  fn resolve(&mut self, result: Result<DeliveryReport, SendError>) {
+    if self.result.is_some() {
+      return;
+    }
+
+    self.result = Some(result);
+    for waker in self.wakers.drain(..) {
+      waker.wake();
+    }
+  }
+}
+
+// Returned from `AckUdp::send`. Await it to learn how the send ended, or peek at `status` without waiting.
+#[derive(Debug, Clone)]
+pub struct DeliveryHandle(Arc<Mutex<AckUdpDatagramOutStatus>>);
+
+impl DeliveryHandle {
+  pub(crate) fn new(status: Arc<Mutex<AckUdpDatagramOutStatus>>) -> Self {
+    DeliveryHandle(status)
+  }
+
+  pub fn datagram_id(&self) -> [u8; 5] {
+    self.0.lock().datagram_id
+  }
+
+  pub fn status(&self) -> AckUdpDatagramOutStatusEnum {
+    self.0.lock().status
+  }
+
+  // `None` while the datagram is still pending
+  pub fn try_result(&self) -> Option<Result<DeliveryReport, SendError>> {
+    self.0.lock().result.clone()
+  }
+}
+
+impl Future for DeliveryHandle {
+  type Output = Result<DeliveryReport, SendError>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+    let mut status = self.0.lock();
+    if let Some(result) = &status.result {
+      return Poll::Ready(result.clone());
+    }
+
+    if !status.wakers.iter().any(|w| w.will_wake(cx.waker())) {
+      status.wakers.push(cx.waker().clone());
+    }
+
+    Poll::Pending
+  }
+}
 
 #[derive(Debug, Clone)]
 pub struct AckUdpDatagram {
   pub id: [u8; 5],
   pub address: SocketAddr,
   pub segments_count: u32,
+  pub sequence: u32,
+  pub epoch: u32,
+  pub channel: u8,
   pub segments: Arc<Mutex<HashMap<u32, AckUdpPacket>>>,
   
-  pub segments_got:  Arc<Mutex<Vec<u32>>>,  // Only for INcome datagrams
+  pub segments_got:  Arc<Mutex<BTreeSet<u32>>>,  // Only for INcome datagrams
+  pub segment_size: Option<usize>, // Only for INcome datagrams, payload size of every segment but the last once one of them arrived
+  pub cumulative_ack: Arc<Mutex<u32>>, // Every segment below this index is received (INcome) or ACKed (OUTcome)
 
   pub segments_acks:  Arc<Mutex<HashSet<u32>>>, // Only for OUTcome datagrams
+  pub segments_sent: Arc<Mutex<HashMap<u32, SegmentSent>>>, // Only for OUTcome datagrams
+  pub segments_pending: Arc<Mutex<BTreeSet<u32>>>, // Only for OUTcome datagrams, waiting for the congestion window to (re)send
   pub checks_failure_count: u16, // Only for OUTcome datagrams
+  pub created_at: Instant,
+
+  pub last_active: Instant,
+}
 
-  pub last_active: DateTime<Utc>,
+#[derive(Debug, Clone, Copy)]
+pub struct SegmentSent {
+  pub sent_at: Instant,
+  pub retransmitted: bool,
+  pub in_flight: bool, // Sent, not ACKed and not declared lost yet
 }
 
 impl AckUdpDatagram {
-  pub fn ack_segment(&mut self, ids: Vec<u32>) -> bool {
+  // `sequence` is the sequence number and its epoch, as `AckUdpPeer::next_sequence` returns them
+  pub fn new_out(id: [u8; 5], address: SocketAddr, channel: u8, sequence: (u32, u32), segments: HashMap<u32, AckUdpPacket>) -> Self {
+    let segments_count = segments.len() as u32;
+    let (sequence, epoch) = sequence;
+
+    AckUdpDatagram {
+      id,
+      address,
+      segments_count,
+      sequence,
+      epoch,
+      channel,
+      segments_pending: Arc::new(Mutex::new(segments.keys().copied().collect())),
+      segments: Arc::new(Mutex::new(segments)),
+      segments_got: Arc::new(Mutex::new(BTreeSet::new())),
+      segment_size: None,
+      cumulative_ack: Arc::new(Mutex::new(0)),
+      segments_acks: Arc::new(Mutex::new(HashSet::new())),
+      segments_sent: Arc::new(Mutex::new(HashMap::new())),
+      checks_failure_count: 0,
+      created_at: Instant::now(),
+      last_active: Instant::now()
+    }
+  }
+
+  // Index and payload size of the next segment waiting to be (re)sent
+  pub fn next_pending(&self) -> Option<(u32, usize)> {
+    let seg_index = *self.segments_pending.lock().first()?;
+
+    Some((seg_index, self.segment_size(seg_index)))
+  }
+
+  // Takes the segment out of pending and marks it in flight. `None` if it got ACKed in the meantime.
+  // Locks are always taken in the order segments_sent -> segments_acks -> segments_pending -> segments.
+  pub fn take_pending(&self, seg_index: u32) -> Option<AckUdpPacket> {
+    let mut segments_sent = self.segments_sent.lock();
+    let segments_acks = self.segments_acks.lock();
+    if !self.segments_pending.lock().remove(&seg_index) || segments_acks.contains(&seg_index) {
+      return None;
+    }
+
+    let retransmitted = segments_sent.contains_key(&seg_index);
+    segments_sent.insert(seg_index, SegmentSent { sent_at: Instant::now(), retransmitted, in_flight: true });
+
+    self.segments.lock().get(&seg_index).cloned()
+  }
+
+  // Round trip time of the latest sent segment among the newly ACKed ones. Retransmitted segments
+  // are skipped (Karn's algorithm): there is no telling which transmission the ACK belongs to.
+  pub fn rtt_sample(&self, acks: &[u32]) -> Option<Duration> {
+    let segments_sent = self.segments_sent.lock();
+    let segments_acks = self.segments_acks.lock();
+
+    acks.iter()
+      .filter(|seg_index| !segments_acks.contains(seg_index))
+      .filter_map(|seg_index| segments_sent.get(seg_index))
+      .filter(|sent| !sent.retransmitted)
+      .map(|sent| sent.sent_at)
+      .max()
+      .map(|sent_at| sent_at.elapsed())
+  }
+
+  // Segments the SACK acknowledges that weren't ACKed before
+  pub fn sack_segments(&self, sack: &AckUdpSack) -> Vec<u32> {
+    let acked_below = *self.cumulative_ack.lock();
+    let segments_acks = self.segments_acks.lock();
+
+    let cumulative = sack.cumulative.min(self.segments_count);
+    let ranges = sack.ranges.iter().flat_map(|&(start, end)| start.max(cumulative)..end.min(self.segments_count));
+
+    (acked_below..cumulative)
+      .chain(ranges)
+      .filter(|seg_index| !segments_acks.contains(seg_index))
+      .collect()
+  }
+
+  // Receive state of an INcome datagram, at most `max_ranges` ranges above the cumulative ACK
+  pub fn received_sack(&self, max_ranges: usize) -> AckUdpSack {
+    let segments_got = self.segments_got.lock();
+    let mut cumulative = self.cumulative_ack.lock();
+    while segments_got.contains(&cumulative) {
+      *cumulative += 1;
+    }
+
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for &seg_index in segments_got.range(*cumulative..) {
+      match ranges.last_mut() {
+        Some((_, end)) if *end == seg_index => *end += 1,
+        _ => {
+          if ranges.len() == max_ranges {
+            break;
+          }
+          ranges.push((seg_index, seg_index + 1));
+        }
+      }
+    }
+
+    AckUdpSack { cumulative: *cumulative, ranges }
+  }
+
+  // Returns whether every segment is ACKed now and how many in flight bytes these ACKs freed
+  pub fn ack_segment(&mut self, ids: Vec<u32>) -> (bool, usize) {
+    let mut segments_sent = self.segments_sent.lock();
+    let mut segments_acks = self.segments_acks.lock();
+    let mut segments_pending = self.segments_pending.lock();
+
+    let mut freed = 0;
+    for id in ids {
+      if id >= self.segments_count || !segments_acks.insert(id) {
+        continue;
+      }
+
+      segments_pending.remove(&id);
+      if let Some(sent) = segments_sent.get_mut(&id) {
+        if sent.in_flight {
+          sent.in_flight = false;
+          freed += self.segment_size(id);
+        }
+      }
+    }
+
+    let mut cumulative = self.cumulative_ack.lock();
+    while segments_acks.contains(&cumulative) {
+      *cumulative += 1;
+    }
+
+    (segments_acks.len() == self.segments_count as usize, freed)
+  }
+
+  // Puts every in flight segment back to pending, returns the bytes taken out of flight
+  pub fn mark_lost(&self) -> usize {
+    let mut segments_sent = self.segments_sent.lock();
+    let segments_acks = self.segments_acks.lock();
+    let mut segments_pending = self.segments_pending.lock();
+
+    let mut freed = 0;
+    for (id, sent) in segments_sent.iter_mut().filter(|(id, sent)| sent.in_flight && !segments_acks.contains(id)) {
+      sent.in_flight = false;
+      freed += self.segment_size(*id);
+      segments_pending.insert(*id);
+    }
+
+    freed
+  }
+
+  // Puts the given in flight segments back to pending, returns the bytes taken out of flight
+  pub fn mark_lost_segments(&self, ids: &[u32]) -> usize {
+    let mut segments_sent = self.segments_sent.lock();
+    let segments_acks = self.segments_acks.lock();
+    let mut segments_pending = self.segments_pending.lock();
+
+    let mut freed = 0;
     for id in ids {
-      self.segments_acks.lock().insert(id);
+      match segments_sent.get_mut(id) {
+        Some(sent) if sent.in_flight && !segments_acks.contains(id) => {
+          sent.in_flight = false;
+          freed += self.segment_size(*id);
+          segments_pending.insert(*id);
+        }
+        _ => {}
+      }
+    }
+
+    freed
+  }
+
+  // INcome datagrams: every segment but the last one is as big as the first of them that arrived, the last one
+  // isn't bigger. Once that size is known the whole datagram has to fit into `max_datagram_size`.
+  pub fn admit_segment(&mut self, packet: &AckUdpPacket, max_datagram_size: usize) -> Result<(), RejectReason> {
+    let is_last = packet.seg_index + 1 == self.segments_count;
+    let size = packet.payload.len();
+    match self.segment_size {
+      Some(segment_size) if size == segment_size || (is_last && size < segment_size) => Ok(()),
+      Some(_) => Err(RejectReason::Malformed),
+      None if is_last => Ok(()),
+      None if size < MIN_SEGMENT_SIZE => Err(RejectReason::Malformed),
+      None => {
+        let last_size = self.segments.lock().get(&(self.segments_count - 1)).map_or(size, |last| last.payload.len());
+        if last_size > size {
+          return Err(RejectReason::Malformed);
+        }
+        if (self.segments_count as usize - 1).saturating_mul(size).saturating_add(last_size) > max_datagram_size {
+          return Err(RejectReason::TooBig);
+        }
+
+        self.segment_size = Some(size);
+        Ok(())
+      }
+    }
+  }
+
+  // Missing segments that count as lost once `seg_index` arrives: `NACK_THRESHOLD` later segments got here before
+  // them. Each is reported once, by the segment that crosses the threshold. Called before `seg_index` is recorded.
+  pub fn newly_lost(&self, seg_index: u32, limit: usize) -> Vec<u32> {
+    let segments_got = self.segments_got.lock();
+    if segments_got.contains(&seg_index) {
+      return vec![];
     }
 
-    self.segments_acks.lock().len() == self.segments_count as usize
+    let mut latest: Vec<u32> = segments_got.iter().rev().take(NACK_THRESHOLD).copied().collect();
+    let before = latest.get(NACK_THRESHOLD - 1).copied();
+    latest.push(seg_index);
+    latest.sort_unstable_by(|a, b| b.cmp(a));
+    let Some(after) = latest.get(NACK_THRESHOLD - 1).copied() else {
+      return vec![];
+    };
+
+    (before.unwrap_or(0)..after)
+      .filter(|index| !segments_got.contains(index))
+      .take(limit)
+      .collect()
+  }
+
+  pub fn bytes_in_flight(&self) -> usize {
+    self.segments_sent.lock().iter()
+      .filter(|(_, sent)| sent.in_flight)
+      .map(|(id, _)| self.segment_size(*id))
+      .sum()
   }
 
-  pub fn form_payload(&mut self) -> Vec<u8> {
+  pub fn has_in_flight(&self) -> bool {
+    self.segments_sent.lock().values().any(|sent| sent.in_flight)
+  }
+
+  // What every segment held so far is charged to the receive window
+  pub fn buffered_bytes(&self) -> usize {
+    self.segments.lock().values().map(|packet| packet.buffered_size()).sum()
+  }
+
+  fn segment_size(&self, seg_index: u32) -> usize {
+    self.segments.lock().get(&seg_index).map(|packet| packet.payload.len()).unwrap_or(0)
+  }
+
+  // The segments' payloads put back together, `None` if one of them is missing
+  pub fn form_payload(&self) -> Option<Vec<u8>> {
+    let segments = self.segments.lock();
     let mut res = Vec::new();
     for b in 0..self.segments_count {
-      res.extend_from_slice(&self.segments.lock().get(&b).unwrap().payload);
+      res.extend_from_slice(&segments.get(&b)?.payload);
     }
 
-    res
+    Some(res)
   }
+}
 
-  pub fn get_non_ack_segments(&self) -> Vec<AckUdpPacket> {
-    let mut res = vec![];
+// Selective ACK: describes the whole receive state of a datagram in one packet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AckUdpSack {
+  pub cumulative: u32, // Every segment below this index is received
+  pub ranges: Vec<(u32, u32)>, // Segments received above `cumulative`, end exclusive
+}
 
-    let length = self.segments.lock().len();
-    for id in 0..length {
-      if !self.segments_acks.lock().contains(&(id as u32)) {
-        res.push(self.segments.lock().get(&(id as u32)).unwrap().to_owned());
-      }
+impl AckUdpSack {
+  // Whole datagram received
+  pub fn complete(segments_count: u32) -> Self {
+    AckUdpSack { cumulative: segments_count, ranges: vec![] }
+  }
+
+  // Ranges that fit into an ACK next to the window and the cumulative ACK
+  pub fn max_ranges(segment_size: usize) -> usize {
+    segment_size.saturating_sub(8) / 8
+  }
+}
+
+// What a packet carries. Packets with no flag set carry reliable data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PacketFlags(u16);
+
+impl PacketFlags {
+  pub const ACK: PacketFlags = PacketFlags(1 << 0);
+  pub const NACK: PacketFlags = PacketFlags(1 << 1);
+  pub const UNRELIABLE: PacketFlags = PacketFlags(1 << 2); // Data the receiver doesn't ACK
+  pub const DROPPED: PacketFlags = PacketFlags(1 << 3); // The sender gave up on the datagram with this sequence number
+  pub const PING: PacketFlags = PacketFlags(1 << 4); // Keepalive, answered with PING | ACK
+  pub const CLOSE: PacketFlags = PacketFlags(1 << 5); // Ends a session, answered with CLOSE | ACK
+  pub const COMPRESSED: PacketFlags = PacketFlags(1 << 6); // Reserved
+  pub const ENCRYPTED: PacketFlags = PacketFlags(1 << 7); // Payload is sealed with the pre-shared key, see `PacketCipher`
+  pub const CONNECT: PacketFlags = PacketFlags(1 << 8); // Opens a session, answered with CONNECT | ACK
+  pub const HANDSHAKE: PacketFlags = PacketFlags(1 << 9); // Noise handshake message, the only packets not sealed once Noise is on
+  pub const COOKIE: PacketFlags = PacketFlags(1 << 10); // Return routability challenge, echoed back with COOKIE | ACK
+  pub const REJECTED: PacketFlags = PacketFlags(1 << 11); // The receiver won't reassemble the datagram, the sender stops resending it
+
+  // Flags this version knows how to handle, packets with any other one are rejected
+  const SUPPORTED: u16 = 0b1111_1011_1111;
+  // Packets with none of these carry data
+  const CONTROL: u16 = 0b1111_0011_1011;
+
+  pub const fn empty() -> Self {
+    PacketFlags(0)
+  }
+
+  pub fn bits(&self) -> u16 {
+    self.0
+  }
+
+  pub fn remove(&mut self, other: PacketFlags) {
+    self.0 &= !other.0;
+  }
+
+  pub fn contains(&self, other: PacketFlags) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  pub fn is_data(&self) -> bool {
+    self.0 & PacketFlags::CONTROL == 0
+  }
+}
+
+impl TryFrom<u16> for PacketFlags {
+  type Error = AckUdpError;
+
+  fn try_from(bits: u16) -> Result<Self, Self::Error> {
+    if bits & !PacketFlags::SUPPORTED != 0 {
+      return Err(AckUdpError::UnknownFlag(bits & !PacketFlags::SUPPORTED));
     }
 
-    res
+    Ok(PacketFlags(bits))
+  }
+}
+
+impl BitOr for PacketFlags {
+  type Output = PacketFlags;
+
+  fn bitor(self, other: PacketFlags) -> PacketFlags {
+    PacketFlags(self.0 | other.0)
   }
 }
 
@@ -69,26 +841,38 @@ pub struct AckUdpPacket {
   pub datagram_id: [u8; 5],
   pub seg_index: u32,
   pub total_segments: u32,
-  pub ack: u8,
+  pub sequence: u32,
+  pub epoch: u32,
+  pub channel: u8,
+  pub flags: PacketFlags,
   pub payload_size: u16,
   pub payload: Vec<u8>,
 }
 
 impl AckUdpPacket {
-  pub fn new_ack(id: [u8; 5], segs: Vec<u32>) -> Vec<u8> {
+  // Charged to the receive window while the segment waits for the rest of its datagram
+  pub fn buffered_size(&self) -> usize {
+    self.payload.len() + SEGMENT_OVERHEAD
+  }
+
+  pub fn new_ack(id: [u8; 5], window: u32, sack: &AckUdpSack) -> Vec<u8> {
     let mut payload = vec![];
+    payload.write_u32::<BigEndian>(window).unwrap();
+    payload.write_u32::<BigEndian>(sack.cumulative).unwrap();
   
-    for seg in segs {
-      let mut wtr = vec![];
-      wtr.write_u32::<BigEndian>(seg).unwrap();
-      payload.extend_from_slice(&wtr);
+    for &(start, end) in &sack.ranges {
+      payload.write_u32::<BigEndian>(start).unwrap();
+      payload.write_u32::<BigEndian>(end).unwrap();
     }
 
     let packet = AckUdpPacket { 
       datagram_id: id,
       seg_index: 0,
       total_segments: 1,
-      ack: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags: PacketFlags::ACK,
       payload_size: payload.len() as u16, 
       payload
     };
@@ -98,62 +882,612 @@ impl AckUdpPacket {
     bytes
   }
 
-  pub fn get_acks(&self) -> Vec<u32> {
-    let mut res = vec![];
-    let tmp: Vec<&[u8]> = self.payload.chunks(4).collect();
+  // Receive window advertised by an ACK, `None` if the payload is too short to carry one
+  pub fn get_window(&self) -> Option<u32> {
+    self.payload.get(..4).map(BigEndian::read_u32)
+  }
+
+  // `None` if the payload is too short to carry a cumulative ACK
+  pub fn get_acks(&self) -> Option<AckUdpSack> {
+    let cumulative = BigEndian::read_u32(self.payload.get(4..8)?);
+    let ranges = self.payload[8..].chunks_exact(8)
+      .map(|range| (BigEndian::read_u32(&range[..4]), BigEndian::read_u32(&range[4..])))
+      .collect();
+
+    Some(AckUdpSack { cumulative, ranges })
+  }
+  // Splits `buf` into segments of one datagram, an empty `buf` still makes one (empty) segment.
+  // `sequence` is the sequence number and its epoch, (0, 0) for datagrams without one.
+  pub fn split(buf: &[u8], segment_size: usize, datagram_id: [u8; 5], channel: u8, sequence: (u32, u32), flags: PacketFlags) -> HashMap<u32, AckUdpPacket> {
+    let (sequence, epoch) = sequence;
+    let segments_count = buf.len().div_ceil(segment_size).max(1) as u32;
+
+    let mut segments = HashMap::new();
+    for index in 0..segments_count {
+      let start = segment_size * index as usize;
+      let end = (start + segment_size).min(buf.len());
+      let payload = &buf[start..end];
 
-    for seg in tmp {
-      let rdr = Cursor::new(seg).read_u32::<BigEndian>().unwrap();
-      res.push(rdr);
+      segments.insert(index, AckUdpPacket { 
+        datagram_id, 
+        seg_index: index, 
+        total_segments: segments_count, 
+        sequence,
+        epoch,
+        channel,
+        flags, 
+        payload_size: payload.len() as u16, 
+        payload: payload.to_vec()
+      });
     }
 
-    res
+    segments
+  }
+
+  // Missing segments beyond what fits into one segment are left to the retransmission timer
+  pub fn new_nack(id: [u8; 5], segs: impl IntoIterator<Item = u32>, segment_size: usize) -> Vec<u8> {
+    let mut payload = vec![];
+    for seg in segs.into_iter().take(segment_size / 4) {
+      payload.write_u32::<BigEndian>(seg).unwrap();
+    }
+
+    let packet = AckUdpPacket { 
+      datagram_id: id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags: PacketFlags::NACK,
+      payload_size: payload.len() as u16, 
+      payload
+    };
+
+    packet.into()
+  }
+
+  pub fn get_nacks(&self) -> Vec<u32> {
+    self.payload.chunks_exact(4).map(BigEndian::read_u32).collect()
+  }
+  // Tells the receiver not to wait for the ordered datagram `sequence` any longer
+  pub fn new_dropped(id: [u8; 5], channel: u8, sequence: u32, epoch: u32) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id: id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence,
+      epoch,
+      channel,
+      flags: PacketFlags::DROPPED,
+      payload_size: 0, 
+      payload: vec![]
+    };
+
+    packet.into()
+  }
+
+  // `flags` is PING or PING | ACK
+  pub fn new_ping(flags: PacketFlags) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id: [0; 5],
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags,
+      payload_size: 0, 
+      payload: vec![]
+    };
+
+    packet.into()
+  }
+
+  // Session handshake, `flags` is CONNECT or CONNECT | ACK
+  pub fn new_connect(session_id: [u8; 5], segment_size: usize, flags: PacketFlags) -> Vec<u8> {
+    let mut payload = vec![];
+    payload.write_u16::<BigEndian>(segment_size as u16).unwrap();
+
+    let packet = AckUdpPacket { 
+      datagram_id: session_id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags,
+      payload_size: payload.len() as u16, 
+      payload
+    };
+
+    packet.into()
+  }
+
+  // Segment size offered by a CONNECT, `None` if it's missing or too small to carry an ACK
+  pub fn get_segment_size(&self) -> Option<usize> {
+    let segment_size = BigEndian::read_u16(self.payload.get(..2)?) as usize;
+
+    (segment_size >= MIN_SEGMENT_SIZE).then_some(segment_size)
+  }
+
+  // `step` is the message's position in the handshake pattern
+  pub fn new_handshake(handshake_id: [u8; 5], step: u32, message: &[u8]) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id: handshake_id,
+      seg_index: step,
+      total_segments: 3,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags: PacketFlags::HANDSHAKE,
+      payload_size: message.len() as u16, 
+      payload: message.to_vec()
+    };
+
+    packet.into()
+  }
+
+  // `flags` is COOKIE or COOKIE | ACK, `datagram_id` the one of the datagram the challenge is about
+  pub fn new_cookie(datagram_id: [u8; 5], cookie: &[u8], flags: PacketFlags) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags,
+      payload_size: cookie.len() as u16, 
+      payload: cookie.to_vec()
+    };
+
+    packet.into()
+  }
+
+  pub fn new_rejected(datagram_id: [u8; 5], reason: RejectReason) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags: PacketFlags::REJECTED,
+      payload_size: 1, 
+      payload: vec![reason as u8]
+    };
+
+    packet.into()
+  }
+
+  pub fn get_reject_reason(&self) -> RejectReason {
+    self.payload.first().copied().map(RejectReason::from).unwrap_or(RejectReason::Unknown)
+  }
+
+  // `flags` is CLOSE or CLOSE | ACK
+  pub fn new_close(session_id: [u8; 5], flags: PacketFlags) -> Vec<u8> {
+    let packet = AckUdpPacket { 
+      datagram_id: session_id,
+      seg_index: 0,
+      total_segments: 1,
+      sequence: 0,
+      epoch: 0,
+      channel: 0,
+      flags,
+      payload_size: 0, 
+      payload: vec![]
+    };
+
+    packet.into()
   }
 }
 
-impl From<Vec<u8>> for AckUdpPacket {
-  fn from(raw_packet: Vec<u8>) -> Self {
-    let payload_size: Vec<u8> = raw_packet[14..16].try_into().unwrap();
-    let rdr_payload_size = Cursor::new(payload_size).read_u16::<BigEndian>().unwrap();
+impl TryFrom<&[u8]> for AckUdpPacket {
+  type Error = AckUdpError;
+
+  fn try_from(raw_packet: &[u8]) -> Result<Self, Self::Error> {
+    if raw_packet.len() < HEADER_SIZE {
+      return Err(AckUdpError::Truncated { expected: HEADER_SIZE, actual: raw_packet.len() });
+    }
+
+    if raw_packet[..2] != MAGIC {
+      return Err(AckUdpError::BadMagic);
+    }
+    if raw_packet[2] != PROTOCOL_VERSION {
+      return Err(AckUdpError::UnsupportedVersion(raw_packet[2]));
+    }
 
-    let total_segments: Vec<u8> = raw_packet[9..13].try_into().unwrap();
-    let rdr_total_segments = Cursor::new(total_segments).read_u32::<BigEndian>().unwrap();
+    let mut rdr = Cursor::new(&raw_packet[3..HEADER_SIZE]);
+    let checksum = rdr.read_u32::<BigEndian>()?;
+    let actual = packet_checksum(raw_packet);
+    if checksum != actual {
+      return Err(AckUdpError::BadChecksum { expected: checksum, actual });
+    }
 
-    let seg_index: Vec<u8> = raw_packet[5..9].try_into().unwrap();
-    let rdr_seg_index = Cursor::new(seg_index).read_u32::<BigEndian>().unwrap();
+    let flags = PacketFlags::try_from(rdr.read_u16::<BigEndian>()?)?;
+    let mut datagram_id = [0; 5];
+    rdr.read_exact(&mut datagram_id)?;
+    let seg_index = rdr.read_u32::<BigEndian>()?;
+    let total_segments = rdr.read_u32::<BigEndian>()?;
+    let sequence = rdr.read_u32::<BigEndian>()?;
+    let epoch = rdr.read_u32::<BigEndian>()?;
+    let channel = rdr.read_u8()?;
+    let payload_size = rdr.read_u16::<BigEndian>()?;
 
-    AckUdpPacket { 
-      datagram_id: raw_packet[..5].try_into().unwrap(), 
-      seg_index: rdr_seg_index, 
-      ack: raw_packet[13],
-      payload_size: rdr_payload_size, 
-      total_segments: rdr_total_segments,
-      payload: raw_packet[16..(rdr_payload_size + 16) as usize].try_into().unwrap(),
+    let payload = &raw_packet[HEADER_SIZE..];
+    if payload.len() != payload_size as usize {
+      return Err(AckUdpError::BadLength { payload_size, actual: payload.len() });
+    }
+    if seg_index >= total_segments {
+      return Err(AckUdpError::BadSegment { seg_index, total_segments });
     }
+
+    Ok(AckUdpPacket { 
+      datagram_id, 
+      seg_index, 
+      flags,
+      payload_size, 
+      total_segments,
+      sequence,
+      epoch,
+      channel,
+      payload: payload.to_vec(),
+    })
   }
 }
 
-impl Into<Vec<u8>> for AckUdpPacket {
-  fn into(self) -> Vec<u8> {
+impl From<AckUdpPacket> for Vec<u8> {
+  fn from(packet: AckUdpPacket) -> Self {
     let mut result: Vec<u8> = vec![];
-    result.extend_from_slice(&self.datagram_id);
+    result.extend_from_slice(&MAGIC);
+    result.push(PROTOCOL_VERSION);
+    result.extend_from_slice(&[0; 4]); // Checksum, filled in once the rest is written
+
+    let mut wtr_flags = vec![];
+    wtr_flags.write_u16::<BigEndian>(packet.flags.bits()).unwrap();
+    result.extend_from_slice(&wtr_flags);
+
+    result.extend_from_slice(&packet.datagram_id);
     
     let mut wtr_seg_index = vec![];
-    wtr_seg_index.write_u32::<BigEndian>(self.seg_index).unwrap();
+    wtr_seg_index.write_u32::<BigEndian>(packet.seg_index).unwrap();
     result.extend_from_slice(&wtr_seg_index);
 
     let mut wtr_total_segments = vec![];
-    wtr_total_segments.write_u32::<BigEndian>(self.total_segments).unwrap();
+    wtr_total_segments.write_u32::<BigEndian>(packet.total_segments).unwrap();
     result.extend_from_slice(&wtr_total_segments);
 
-    result.push(self.ack);
+    let mut wtr_sequence = vec![];
+    wtr_sequence.write_u32::<BigEndian>(packet.sequence).unwrap();
+    result.extend_from_slice(&wtr_sequence);
+
+    let mut wtr_epoch = vec![];
+    wtr_epoch.write_u32::<BigEndian>(packet.epoch).unwrap();
+    result.extend_from_slice(&wtr_epoch);
+
+    result.push(packet.channel);
 
     let mut wtr_payload_size = vec![];
-    wtr_payload_size.write_u16::<BigEndian>(self.payload_size).unwrap();
+    wtr_payload_size.write_u16::<BigEndian>(packet.payload_size).unwrap();
     result.extend_from_slice(&wtr_payload_size);
 
-    result.extend_from_slice(&self.payload);
+    result.extend_from_slice(&packet.payload);
+
+    fill_checksum(&mut result);
 
     result
   }
 }
+
+// Once everything else in `raw_packet` is written
+pub fn fill_checksum(raw_packet: &mut [u8]) {
+  let checksum = packet_checksum(raw_packet);
+  BigEndian::write_u32(&mut raw_packet[3..7], checksum);
+}
+
+// Covers everything around the checksum field, header and payload alike
+fn packet_checksum(raw_packet: &[u8]) -> u32 {
+  crc32c::crc32c_append(crc32c::crc32c(&raw_packet[..3]), &raw_packet[7..])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn raw_segment() -> Vec<u8> {
+    let mut segments = AckUdpPacket::split(&[7; 100], 40, [1, 2, 3, 4, 5], 2, (9, 3), PacketFlags::UNRELIABLE);
+    segments.remove(&1).unwrap().into()
+  }
+
+  // Fixes the checksum up after the packet was changed on purpose
+  fn reseal(raw_packet: &mut [u8]) {
+    let checksum = packet_checksum(raw_packet);
+    raw_packet[3..7].copy_from_slice(&checksum.to_be_bytes());
+  }
+
+  #[test]
+  fn delivered_window_keeps_the_latest_ids() {
+    let mut window = DeliveredWindow::default();
+    for id in 0..4u8 {
+      window.insert([id; 5], 3);
+    }
+    window.insert([3; 5], 3);
+    assert!(!window.contains(&[0; 5]));
+    assert!((1..4u8).all(|id| window.contains(&[id; 5])));
+
+    window.expire(Duration::from_secs(60));
+    assert!(window.contains(&[1; 5]));
+    window.expire(Duration::ZERO);
+    assert!(window.is_empty() && !window.contains(&[3; 5]));
+  }
+
+  #[test]
+  fn ready_queue_reads_channels_apart() {
+    let a = "127.0.0.1:1".parse().unwrap();
+    let b = "127.0.0.1:2".parse().unwrap();
+    let mut queue = ReadyQueue::default();
+    queue.push(a, 1, None, vec![1]);
+    queue.push(b, 0, None, vec![2]);
+    queue.push(a, 0, None, vec![3]);
+    queue.push(a, 1, None, vec![4]);
+
+    assert_eq!(queue.take(Some(0), |address| address == a).unwrap().2, [3]);
+    assert_eq!(queue.take(Some(2), |_| true), None);
+    // Any channel: the oldest one first
+    assert_eq!(queue.take(None, |_| true).unwrap().2, [1]);
+    assert_eq!(queue.take(None, |address| address == a).unwrap().2, [4]);
+    assert_eq!(queue.take(None, |_| true).unwrap().2, [2]);
+    assert!(queue.channels.is_empty());
+  }
+
+  fn out_datagram(segments_count: usize) -> AckUdpDatagram {
+    let address = "127.0.0.1:1".parse().unwrap();
+    let segments = AckUdpPacket::split(&vec![0; segments_count * 40], 40, [1; 5], 0, (0, 0), PacketFlags::empty());
+
+    AckUdpDatagram::new_out([1; 5], address, 0, (0, 0), segments)
+  }
+
+  #[test]
+  fn received_sack_describes_gaps() {
+    let datagram = out_datagram(8);
+    datagram.segments_got.lock().extend([0, 1, 3, 4, 6]);
+    assert_eq!(datagram.received_sack(8), AckUdpSack { cumulative: 2, ranges: vec![(3, 5), (6, 7)] });
+    // The ranges closest to the cumulative ACK are kept
+    assert_eq!(datagram.received_sack(1), AckUdpSack { cumulative: 2, ranges: vec![(3, 5)] });
+
+    datagram.segments_got.lock().insert(2);
+    assert_eq!(datagram.received_sack(8), AckUdpSack { cumulative: 5, ranges: vec![(6, 7)] });
+  }
+
+  #[test]
+  fn sack_segments_lists_newly_acked_segments() {
+    let mut datagram = out_datagram(8);
+    // Ranges past the end of the datagram are ignored
+    let sack = AckUdpSack { cumulative: 2, ranges: vec![(3, 5), (6, 100)] };
+    let acked = datagram.sack_segments(&sack);
+    assert_eq!(acked, [0, 1, 3, 4, 6, 7]);
+
+    let (is_full_ack, _) = datagram.ack_segment(acked);
+    assert!(!is_full_ack);
+    assert!(datagram.sack_segments(&sack).is_empty());
+    assert_eq!(datagram.sack_segments(&AckUdpSack::complete(8)), [2, 5]);
+  }
+
+  #[test]
+  fn form_payload_needs_every_segment() {
+    let datagram = out_datagram(3);
+    assert_eq!(datagram.form_payload(), Some(vec![0; 120]));
+    datagram.segments.lock().remove(&1);
+    assert_eq!(datagram.form_payload(), None);
+  }
+
+  fn in_segment(seg_index: u32, segments_count: u32, size: usize) -> AckUdpPacket {
+    let mut segments = AckUdpPacket::split(&vec![0; size * segments_count as usize], size, [2; 5], 0, (0, 0), PacketFlags::empty());
+    segments.remove(&seg_index).unwrap()
+  }
+
+  #[test]
+  fn in_segments_keep_to_the_first_size() {
+    let mut datagram = out_datagram(4);
+    // Opened by a 20 bytes segment, the later ones can't be bigger
+    datagram.segment_size = Some(20);
+    assert_eq!(datagram.admit_segment(&in_segment(1, 4, 20), 1000), Ok(()));
+    assert_eq!(datagram.admit_segment(&in_segment(2, 4, 400), 1000), Err(RejectReason::Malformed));
+    assert_eq!(datagram.admit_segment(&in_segment(3, 4, 10), 1000), Ok(()));
+    assert_eq!(datagram.admit_segment(&in_segment(3, 4, 30), 1000), Err(RejectReason::Malformed));
+
+    // Opened by the last one, the size is only known with the next one
+    let mut datagram = out_datagram(4);
+    datagram.segments.lock().retain(|seg_index, _| *seg_index == 3);
+    assert_eq!(datagram.admit_segment(&in_segment(0, 4, 8), 1000), Err(RejectReason::Malformed));
+    assert_eq!(datagram.admit_segment(&in_segment(0, 4, 400), 1000), Err(RejectReason::TooBig));
+    assert_eq!(datagram.admit_segment(&in_segment(0, 4, 200), 1000), Ok(()));
+    assert_eq!(datagram.segment_size, Some(200));
+  }
+
+  #[test]
+  fn segments_are_nacked_after_three_later_ones() {
+    let datagram = out_datagram(10);
+    let receive = |seg_index| {
+      let lost = datagram.newly_lost(seg_index, 100);
+      datagram.segments_got.lock().insert(seg_index);
+      lost
+    };
+    assert!(receive(0).is_empty() && receive(2).is_empty() && receive(3).is_empty());
+    assert_eq!(receive(4), [1]);
+    // Filling the gap late or receiving something twice reports nothing again
+    assert!(receive(1).is_empty() && receive(4).is_empty());
+    assert!(receive(8).is_empty() && receive(9).is_empty());
+    assert_eq!(receive(6), [5]);
+    assert!(receive(7).is_empty());
+  }
+
+  #[test]
+  fn sack_survives_the_wire() {
+    let sack = AckUdpSack { cumulative: 3, ranges: vec![(4, 6), (9, 10)] };
+    let packet = AckUdpPacket::try_from(&AckUdpPacket::new_ack([2; 5], 5000, &sack)[..]).unwrap();
+    assert_eq!(packet.get_acks(), Some(sack));
+    assert_eq!(packet.get_window(), Some(5000));
+  }
+
+  #[test]
+  fn sequence_numbers_wrap_around_to_one() {
+    assert_eq!(sequence_after(u32::MAX), 1);
+    assert!(sequence_before(u32::MAX, 1));
+    assert!(sequence_before(1, 2) && !sequence_before(2, 1) && !sequence_before(2, 2));
+  }
+
+  #[test]
+  fn ordered_inbox_holds_until_the_gap_is_filled() {
+    let mut inbox = OrderedInbox::new(7);
+    assert_eq!(inbox.push(2, vec![2]), Some(vec![]));
+    assert_eq!(inbox.push(3, vec![3]), Some(vec![]));
+    assert!(!inbox.is_idle(Duration::ZERO));
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![vec![1], vec![2], vec![3]]));
+    assert!(inbox.is_idle(Duration::ZERO));
+    // Already read, a retransmit of it is late
+    assert_eq!(inbox.push(2, vec![2]), None);
+  }
+
+  #[test]
+  fn ordered_inbox_skips_dropped_sequences() {
+    let mut inbox = OrderedInbox::new(7);
+    assert!(inbox.skip(7, 2).is_empty());
+    assert_eq!(inbox.push(3, vec![3]), Some(vec![]));
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![vec![1], vec![3]]));
+    // Given up on before it arrived
+    assert_eq!(inbox.push(2, vec![2]), None);
+
+    assert_eq!(inbox.push(5, vec![5]), Some(vec![]));
+    assert_eq!(inbox.skip(7, 4), vec![vec![5]]);
+  }
+
+  #[test]
+  fn ordered_inbox_bounds_what_it_skips() {
+    let mut inbox = OrderedInbox::new(7);
+    // Too far ahead, and already behind
+    assert!(inbox.skip(7, 1 + MAX_SKIP_AHEAD).is_empty());
+    assert!(inbox.skip(7, u32::MAX).is_empty());
+    assert!(inbox.dropped.is_empty());
+
+    for sequence in 2..2 + MAX_SKIP_AHEAD {
+      inbox.skip(7, sequence);
+    }
+    assert_eq!(inbox.dropped.len(), MAX_SKIP_AHEAD as usize - 1);
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![vec![1]]));
+    assert!(inbox.dropped.is_empty());
+  }
+
+  #[test]
+  fn ordered_inbox_expires_missing_sequences() {
+    let mut inbox = OrderedInbox::new(7);
+    inbox.push(3, vec![3]);
+    assert!(inbox.expire(Duration::from_secs(60)).is_empty());
+    assert_eq!(inbox.expire(Duration::ZERO), vec![vec![3]]);
+    assert_eq!(inbox.push(2, vec![2]), None);
+  }
+
+  #[test]
+  fn ordered_inbox_wraps_around() {
+    let mut inbox = OrderedInbox { next_sequence: u32::MAX - 1, ..OrderedInbox::new(7) };
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![]));
+    assert_eq!(inbox.push(u32::MAX, vec![0xff]), Some(vec![]));
+    assert_eq!(inbox.expire(Duration::ZERO), vec![vec![0xff], vec![1]]);
+    assert_eq!(inbox.push(u32::MAX - 1, vec![0xfe]), None);
+  }
+
+  #[test]
+  fn ordered_inbox_restarts_with_its_sender() {
+    let mut inbox = OrderedInbox::new(7);
+    assert_eq!(inbox.restart(7), Some(vec![]));
+    inbox.push(1, vec![1]);
+    inbox.push(3, vec![3]);
+    inbox.push(4, vec![4]);
+    // What was held for the previous run was ACKed, it's read before the new run
+    assert_eq!(inbox.restart(8), Some(vec![vec![3], vec![4]]));
+    assert_eq!(inbox.push(2, vec![2]), Some(vec![]));
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![vec![1], vec![2]]));
+
+    // Stragglers of the previous run are late, they don't start it over again
+    assert_eq!(inbox.restart(7), None);
+    assert!(inbox.skip(7, 3).is_empty());
+    assert_eq!(inbox.push(3, vec![3]), Some(vec![vec![3]]));
+  }
+
+  #[test]
+  fn ordered_inbox_refuses_a_late_first_datagram() {
+    let mut inbox = OrderedInbox::new(7);
+    assert_eq!(inbox.push(1, vec![1]), Some(vec![vec![1]]));
+    assert_eq!(inbox.push(3, vec![3]), Some(vec![]));
+    // A retransmit of 1 arriving after later ones is no restart, whatever is held stays
+    assert_eq!(inbox.restart(7), Some(vec![]));
+    assert_eq!(inbox.push(1, vec![1]), None);
+    assert_eq!(inbox.push(2, vec![2]), Some(vec![vec![2], vec![3]]));
+  }
+
+  #[test]
+  fn ordered_inbox_only_takes_newer_unreliable_datagrams() {
+    let mut inbox = OrderedInbox::new(7);
+    assert!(inbox.accept_newer(3));
+    assert!(!inbox.accept_newer(2) && !inbox.accept_newer(3));
+    assert!(inbox.accept_newer(7));
+  }
+
+  #[test]
+  fn flags_tell_data_from_control_packets() {
+    assert!(PacketFlags::empty().is_data() && PacketFlags::UNRELIABLE.is_data());
+    assert!((PacketFlags::UNRELIABLE | PacketFlags::ENCRYPTED).is_data());
+    assert!(!PacketFlags::ACK.is_data() && !(PacketFlags::PING | PacketFlags::ACK).is_data());
+    assert!(matches!(PacketFlags::try_from(PacketFlags::COMPRESSED.bits()), Err(AckUdpError::UnknownFlag(_))));
+  }
+
+  #[test]
+  fn parses_what_it_writes() {
+    let packet = AckUdpPacket::try_from(&raw_segment()[..]).unwrap();
+    assert_eq!((packet.datagram_id, packet.seg_index, packet.total_segments), ([1, 2, 3, 4, 5], 1, 3));
+    assert_eq!((packet.sequence, packet.epoch, packet.channel, packet.flags), (9, 3, 2, PacketFlags::UNRELIABLE));
+    assert_eq!(packet.payload, [7; 40]);
+  }
+
+  #[test]
+  fn rejects_truncated_packets() {
+    let raw_packet = raw_segment();
+    assert!(matches!(
+      AckUdpPacket::try_from(&raw_packet[..HEADER_SIZE - 1]),
+      Err(AckUdpError::Truncated { expected: HEADER_SIZE, actual }) if actual == HEADER_SIZE - 1
+    ));
+    // The header is intact but the payload size no longer matches
+    let mut raw_packet = raw_packet[..HEADER_SIZE + 10].to_vec();
+    reseal(&mut raw_packet);
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::BadLength { payload_size: 40, actual: 10 })));
+  }
+
+  #[test]
+  fn rejects_corrupted_packets() {
+    let mut raw_packet = raw_segment();
+    raw_packet[HEADER_SIZE + 3] ^= 1;
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::BadChecksum { .. })));
+  }
+
+  #[test]
+  fn rejects_unknown_flags_and_foreign_packets() {
+    let mut raw_packet = raw_segment();
+    raw_packet[7] |= 0x80;
+    reseal(&mut raw_packet);
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::UnknownFlag(0x8000))));
+
+    let mut raw_packet = raw_segment();
+    raw_packet[2] = PROTOCOL_VERSION + 1;
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::UnsupportedVersion(_))));
+    raw_packet[0] = b'X';
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::BadMagic)));
+  }
+
+  #[test]
+  fn rejects_segments_outside_of_the_datagram() {
+    let mut raw_packet = raw_segment();
+    raw_packet[14..18].copy_from_slice(&3u32.to_be_bytes());
+    reseal(&mut raw_packet);
+    assert!(matches!(AckUdpPacket::try_from(&raw_packet[..]), Err(AckUdpError::BadSegment { seg_index: 3, total_segments: 3 })));
+  }
+}
@@ -0,0 +1,68 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::{AckUdp, ChannelMode};
+
+use common::{localhost, socket};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn channels_keep_their_own_guarantees() {
+  let b = socket().await;
+  let mut a = AckUdp::builder()
+    .channel(1, ChannelMode::ReliableOrdered)
+    .channel(2, ChannelMode::Unreliable)
+    .channel(3, ChannelMode::UnreliableSequenced)
+    .bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+
+  // A big transfer on channel 0 doesn't hold channel 1 back
+  let big = vec![7; 2_000_000];
+  a.send(&big, b_addr).unwrap();
+  for i in 0..20u32 {
+    a.send_on(1, &i.to_be_bytes(), b_addr).unwrap().unwrap();
+  }
+  for i in 0..20u32 {
+    assert_eq!(b.recv_on(1).await.unwrap().1, i.to_be_bytes());
+  }
+
+  assert!(a.send_on(2, b"unreliable", b_addr).unwrap().is_none());
+  let (_, datagram) = tokio::time::timeout(Duration::from_secs(5), b.recv_on(2)).await.unwrap().unwrap();
+  assert_eq!(datagram, b"unreliable");
+
+  for i in 0..5u8 {
+    a.send_on(3, &[i], b_addr).unwrap();
+  }
+  let mut last = None;
+  while let Ok(Some((_, datagram))) = tokio::time::timeout(Duration::from_millis(300), b.recv_on(3)).await {
+    assert!(last.is_none_or(|last| datagram[0] > last));
+    last = Some(datagram[0]);
+  }
+  assert!(last.is_some());
+
+  assert_eq!(b.recv_timeout(Duration::from_secs(30)).await.unwrap().1.len(), big.len());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_unread_channel_doesnt_hold_the_others_back() {
+  let b = socket().await;
+  let mut a = AckUdp::builder().channel(1, ChannelMode::ReliableOrdered).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+
+  for i in 0..100u32 {
+    a.send_on(1, &i.to_be_bytes(), b_addr).unwrap().unwrap().await.unwrap();
+  }
+  a.send(b"zero", b_addr).unwrap().await.unwrap();
+  assert_eq!(b.recv_on(0).await.unwrap().1, b"zero");
+
+  // Nothing on channel 1 was skipped meanwhile
+  for i in 0..100u32 {
+    assert_eq!(b.recv().await.unwrap().1, i.to_be_bytes());
+  }
+  assert!(b.try_recv().is_none());
+}
+
+#[tokio::test]
+async fn channel_zero_has_to_be_reliable() {
+  assert!(AckUdp::builder().channel(0, ChannelMode::Unreliable).bind(localhost()).await.is_err());
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use common::{proxy, socket};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn corrupted_segments_are_resent() {
+  let b = socket().await;
+  let mut a = socket().await;
+  // Flips a bit in 5% of the packets either way
+  let proxy = proxy(b.local_addr().unwrap(), |packet| {
+    if rand::random::<f64>() < 0.05 {
+      packet[rand::random::<usize>() % packet.len()] ^= 0x40;
+    }
+    true
+  }).await;
+  let big: Vec<u8> = (0..300_000u32).map(|v| (v * 13) as u8).collect();
+
+  a.send(&big, proxy).unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(20)).await.unwrap().1, big);
+  assert!(b.stats().corrupted_packets.load(Ordering::Relaxed) > 0);
+}
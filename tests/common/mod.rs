@@ -0,0 +1,111 @@
+// Helpers shared by the integration tests, not every test file uses all of them
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::Duration};
+
+use ack_udp::AckUdp;
+use parking_lot::Mutex;
+use tokio::net::UdpSocket;
+
+pub const HEADER_SIZE: usize = 33;
+
+pub const UNRELIABLE: u16 = 1 << 2;
+pub const DROPPED: u16 = 1 << 3;
+pub const ENCRYPTED: u16 = 1 << 7;
+pub const REJECTED: u16 = 1 << 11;
+
+pub fn localhost() -> SocketAddr {
+  "127.0.0.1:0".parse().unwrap()
+}
+
+pub async fn socket() -> AckUdp {
+  AckUdp::new(localhost()).await.unwrap()
+}
+
+// Forwards between `target` and whoever last sent to the proxy. `mangle` may change a packet on the way,
+// returning `false` drops it.
+pub async fn proxy(target: SocketAddr, mangle: impl Fn(&mut [u8]) -> bool + Send + 'static) -> SocketAddr {
+  let socket = UdpSocket::bind(localhost()).await.unwrap();
+  let address = socket.local_addr().unwrap();
+  let peer: Arc<Mutex<Option<SocketAddr>>> = Arc::new(Mutex::new(None));
+  tokio::spawn(async move {
+    let mut buf = vec![0; 65536];
+    loop {
+      let Ok((length, from)) = socket.recv_from(&mut buf).await else {
+        return;
+      };
+      if !mangle(&mut buf[..length]) {
+        continue;
+      }
+
+      let to = if from == target {
+        match *peer.lock() {
+          Some(peer) => peer,
+          None => continue
+        }
+      }
+      else {
+        *peer.lock() = Some(from);
+        target
+      };
+      let _ = socket.send_to(&buf[..length], to).await;
+    }
+  });
+
+  address
+}
+
+// Drops `loss` of the packets going either way, evenly spread so every run loses the same ones
+pub async fn lossy_proxy(target: SocketAddr, loss: f64) -> SocketAddr {
+  let forwarded = AtomicU64::new(0);
+  proxy(target, move |_| {
+    let count = forwarded.fetch_add(1, Ordering::Relaxed) as f64;
+    ((count + 1.0) * loss).floor() == (count * loss).floor()
+  }).await
+}
+
+// Checks `condition` until it holds, for up to 2 seconds
+pub async fn eventually(condition: impl Fn() -> bool) -> bool {
+  for _ in 0..200 {
+    if condition() {
+      return true;
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+  }
+
+  condition()
+}
+
+// A plain packet on channel 0, as a peer would send it. Sequenced ones are in epoch 1.
+pub fn craft(flags: u16, id: [u8; 5], seg_index: u32, total_segments: u32, sequence: u32, payload: &[u8]) -> Vec<u8> {
+  let mut packet = b"AU\x02\0\0\0\0".to_vec();
+  packet.extend_from_slice(&flags.to_be_bytes());
+  packet.extend_from_slice(&id);
+  packet.extend_from_slice(&seg_index.to_be_bytes());
+  packet.extend_from_slice(&total_segments.to_be_bytes());
+  packet.extend_from_slice(&sequence.to_be_bytes());
+  packet.extend_from_slice(&u32::from(sequence != 0).to_be_bytes());
+  packet.push(0);
+  packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+  packet.extend_from_slice(payload);
+  checksum(&mut packet);
+
+  packet
+}
+
+// First segment of an unsequenced reliable datagram
+pub fn craft_data(id: [u8; 5], total_segments: u32, payload: &[u8]) -> Vec<u8> {
+  craft(0, id, 0, total_segments, 0, payload)
+}
+
+// CRC32C over everything but the checksum itself
+pub fn checksum(packet: &mut [u8]) {
+  let mut covered = packet[..3].to_vec();
+  covered.extend_from_slice(&packet[7..]);
+  let sum = crc32c::crc32c(&covered);
+  packet[3..7].copy_from_slice(&sum.to_be_bytes());
+}
+
+pub fn flags(packet: &[u8]) -> u16 {
+  u16::from_be_bytes([packet[7], packet[8]])
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use std::time::Duration;
+
+use common::{lossy_proxy, socket};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_through_a_lossy_link() {
+  let mut a = socket().await;
+  let b = socket().await;
+  let proxy = lossy_proxy(b.local_addr().unwrap(), 0.02).await;
+  let big: Vec<u8> = (0..1_000_000u32).map(|v| (v * 7) as u8).collect();
+
+  let big_delivery = a.send(&big, proxy).unwrap();
+  let small_delivery = a.send(b"small", proxy).unwrap();
+  let mut received = vec![];
+  for _ in 0..2 {
+    received.push(b.recv_timeout(Duration::from_secs(20)).await.unwrap().1);
+  }
+  assert!(received.contains(&big) && received.contains(&b"small".to_vec()));
+  assert!(big_delivery.await.is_ok() && small_delivery.await.is_ok());
+  assert!(a.peer_congestion_window(proxy).is_some());
+}
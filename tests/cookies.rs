@@ -0,0 +1,76 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ack_udp::AckUdp;
+use tokio::net::UdpSocket;
+
+use common::{craft, craft_data, localhost, lossy_proxy, socket, DROPPED};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unvalidated_segments_are_challenged() {
+  let b = AckUdp::builder().cookie_challenge(true).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  // The answer is small and nothing is kept for it
+  raw.send_to(&craft_data([1, 2, 3, 4, 5], 5, b"x"), b_addr).await.unwrap();
+  let mut buf = [0; 2048];
+  let (length, _) = tokio::time::timeout(Duration::from_secs(1), raw.recv_from(&mut buf)).await.unwrap().unwrap();
+  assert!(length <= 3 * 30);
+  assert_eq!(b.buffered_bytes(), 0);
+  assert_eq!(b.stats().cookie_challenges.load(Ordering::Relaxed), 1);
+
+  // Single segments need no reassembly and still get through
+  raw.send_to(&craft_data([1, 2, 3, 4, 6], 1, b"single"), b_addr).await.unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"single");
+
+  let mut a = socket().await;
+  let proxy = lossy_proxy(b_addr, 0.05).await;
+  let big: Vec<u8> = (0..300_000u32).map(|v| (v * 7) as u8).collect();
+  a.send(&big, proxy).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, big);
+  // Once validated nothing is challenged anymore
+  let challenges = b.stats().cookie_challenges.load(Ordering::Relaxed);
+  a.send(&big, proxy).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, big);
+  assert_eq!(b.stats().cookie_challenges.load(Ordering::Relaxed), challenges);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreliable_senders_and_sessions_echo_cookies() {
+  let b = AckUdp::builder().cookie_challenge(true).accept_sessions(true).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  // Every segment of the first datagram is challenged, the next one gets through
+  let a = socket().await;
+  a.send_unreliable(&[3; 3000], b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  a.send_unreliable(&[4; 3000], b_addr).unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(2)).await.unwrap().1, [4; 3000]);
+  // Segments that arrive after the echo aren't challenged anymore
+  let challenges = b.stats().cookie_challenges.load(Ordering::Relaxed);
+  assert!((1..=8).contains(&challenges));
+
+  // Sessions from unvalidated addresses need an echo first
+  let c = socket().await;
+  let session = tokio::time::timeout(Duration::from_secs(5), c.connect(b_addr)).await.unwrap().unwrap();
+  let accepted = b.accept().await.unwrap();
+  session.send(b"hi").unwrap().await.unwrap();
+  assert_eq!(accepted.recv().await.unwrap(), b"hi");
+  assert!(b.stats().cookie_challenges.load(Ordering::Relaxed) > challenges);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unvalidated_dropped_creates_nothing() {
+  let b = AckUdp::builder().cookie_challenge(true).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  raw.send_to(&craft(DROPPED, [1, 2, 3, 4, 5], 0, 1, 1, &[]), b_addr).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+
+  // Sequence number 1 wasn't given up on, the next one waits for it
+  raw.send_to(&craft(0, [1, 2, 3, 4, 6], 0, 1, 2, b"second"), b_addr).await.unwrap();
+  assert!(b.recv_timeout(Duration::from_millis(300)).await.is_none());
+  raw.send_to(&craft(0, [1, 2, 3, 4, 7], 0, 1, 1, b"first"), b_addr).await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, b"first");
+  assert_eq!(b.recv().await.unwrap().1, b"second");
+}
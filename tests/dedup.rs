@@ -0,0 +1,26 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use common::{craft_data, flags, localhost, socket, HEADER_SIZE};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn resent_datagrams_are_acked_but_read_once() {
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  let datagram = craft_data([4; 5], 1, b"once");
+
+  let mut buf = [0; 2048];
+  for _ in 0..3 {
+    raw.send_to(&datagram, b_addr).await.unwrap();
+    let (length, _) = tokio::time::timeout(Duration::from_secs(1), raw.recv_from(&mut buf)).await.unwrap().unwrap();
+    // ACK
+    assert!(length > HEADER_SIZE && flags(&buf) == 1);
+  }
+
+  assert_eq!(b.recv().await.unwrap().1, b"once");
+  assert!(b.recv_timeout(Duration::from_millis(300)).await.is_none());
+}
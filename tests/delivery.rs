@@ -0,0 +1,38 @@
+mod common;
+
+use std::time::Duration;
+
+use common::socket;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_and_reports() {
+  let mut a = socket().await;
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let big: Vec<u8> = (0..100_000u32).map(|v| v as u8).collect();
+
+  let small_delivery = a.send(b"hello", b_addr).unwrap();
+  let big_delivery = a.send(&big, b_addr).unwrap();
+  let mut received = vec![];
+  for _ in 0..2 {
+    received.push(b.recv_timeout(Duration::from_secs(10)).await.unwrap().1);
+  }
+  assert!(received.contains(&b"hello".to_vec()));
+  assert!(received.contains(&big));
+
+  let report = small_delivery.await.unwrap();
+  assert_eq!((report.address, report.segments_count), (b_addr, 1));
+  assert!(big_delivery.await.unwrap().segments_count > 1);
+  // Resent segments aren't delivered twice
+  assert!(b.recv_timeout(Duration::from_millis(1200)).await.is_none());
+  assert_eq!(b.buffered_bytes(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn delivers_large_datagrams() {
+  let mut a = socket().await;
+  let b = socket().await;
+  a.send(&vec![7; 2_000_000], b.local_addr().unwrap()).unwrap();
+
+  assert_eq!(b.recv_timeout(Duration::from_secs(30)).await.unwrap().1.len(), 2_000_000);
+}
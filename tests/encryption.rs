@@ -0,0 +1,64 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ack_udp::AckUdp;
+use tokio::net::UdpSocket;
+
+use common::{eventually, localhost, lossy_proxy, socket};
+
+async fn psk_socket(key: u8) -> AckUdp {
+  AckUdp::builder().psk([key; 32]).bind(localhost()).await.unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn only_peers_with_the_key_get_through() {
+  let b = psk_socket(7).await;
+  let mut a = psk_socket(7).await;
+  let b_addr = b.local_addr().unwrap();
+  let proxy = lossy_proxy(b_addr, 0.02).await;
+  let big: Vec<u8> = (0..200_000u32).map(|v| (v * 3) as u8).collect();
+  a.send(&big, proxy).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, big);
+
+  let mut plain = socket().await;
+  let mut stranger = psk_socket(8).await;
+  let plain_delivery = plain.send(b"hi", b_addr).unwrap();
+  let stranger_delivery = stranger.send(b"hi", b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(plain_delivery.try_result().is_none() && stranger_delivery.try_result().is_none());
+  assert!(b.try_recv().is_none());
+  assert!(eventually(|| b.stats().unauthenticated_packets.load(Ordering::Relaxed) >= 2).await);
+
+  // Sealed packets are no good to a socket without a key either
+  let _delivery = a.send(b"x", plain.local_addr().unwrap()).unwrap();
+  assert!(eventually(|| plain.stats().unauthenticated_packets.load(Ordering::Relaxed) >= 1).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn captured_packets_are_read_once() {
+  let b = psk_socket(7).await;
+  let mut a = psk_socket(7).await;
+  let b_addr = b.local_addr().unwrap();
+  // Capture what `a` sends by sending it to a plain socket first
+  let sniffer = UdpSocket::bind(localhost()).await.unwrap();
+  let _delivery = a.send(b"once", sniffer.local_addr().unwrap()).unwrap();
+  let mut buf = [0; 2048];
+  let (length, _) = sniffer.recv_from(&mut buf).await.unwrap();
+  let captured = buf[..length].to_vec();
+
+  // From any address
+  for _ in 0..2 {
+    let replayer = UdpSocket::bind(localhost()).await.unwrap();
+    replayer.send_to(&captured, b_addr).await.unwrap();
+    replayer.send_to(&captured, b_addr).await.unwrap();
+  }
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"once");
+  assert!(b.recv_timeout(Duration::from_millis(500)).await.is_none());
+  // The ones after the first, however long they took to get processed
+  assert!(eventually(|| b.stats().replayed_packets.load(Ordering::Relaxed) == 3).await);
+
+  // Reflected back to the sender
+  sniffer.send_to(&captured, a.local_addr().unwrap()).await.unwrap();
+  assert!(eventually(|| a.stats().replayed_packets.load(Ordering::Relaxed) == 1).await);
+}
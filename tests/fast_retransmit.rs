@@ -0,0 +1,33 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::AckUdp;
+use tokio::net::UdpSocket;
+
+use common::{craft, localhost};
+
+const NACK: u16 = 1 << 1;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn nacked_segments_are_resent_right_away() {
+  let mut a = AckUdp::builder()
+    .resend_interval(Duration::from_secs(3))
+    .max_rto(Duration::from_secs(4))
+    .max_resend_attempts(10)
+    .incoming_expiry(Duration::from_secs(20))
+    .bind(localhost()).await.unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  let _delivery = a.send(&vec![1; 3 * a.config().segment_size], raw.local_addr().unwrap()).unwrap();
+
+  let mut buf = [0; 2048];
+  let mut id = [0; 5];
+  for _ in 0..3 {
+    raw.recv_from(&mut buf).await.unwrap();
+    id.copy_from_slice(&buf[9..14]);
+  }
+
+  raw.send_to(&craft(NACK, id, 0, 1, 0, &1u32.to_be_bytes()), a.local_addr().unwrap()).await.unwrap();
+  tokio::time::timeout(Duration::from_millis(500), raw.recv_from(&mut buf)).await.unwrap().unwrap();
+  assert_eq!((&buf[9..14], u32::from_be_bytes(buf[14..18].try_into().unwrap())), (&id[..], 1));
+}
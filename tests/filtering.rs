@@ -0,0 +1,61 @@
+mod common;
+
+use std::{net::IpAddr, sync::atomic::Ordering, time::Duration};
+
+use ack_udp::{AckUdp, AckUdpEvent, IpNet};
+use tokio::net::UdpSocket;
+
+use common::{craft_data, localhost, socket};
+
+fn loopback() -> IpAddr {
+  "127.0.0.1".parse().unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn floods_get_sources_banned() {
+  let b = AckUdp::builder().rate_limit(2000, 500).ban_threshold(50).ban_duration(Duration::from_millis(500)).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  // A well behaved sender stays within the limit
+  let mut a = socket().await;
+  let big = vec![3; 100_000];
+  a.send(&big, b_addr).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, big);
+
+  let mut events = b.subscribe_events();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  for _ in 0..2000 {
+    raw.send_to(&craft_data([1, 2, 3, 4, 5], 1, b"x"), b_addr).await.unwrap();
+  }
+  let event = tokio::time::timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap();
+  assert_eq!(event, AckUdpEvent::PeerBanned(loopback()));
+  assert!(b.ban_remaining(loopback()).is_some());
+  tokio::time::sleep(Duration::from_millis(600)).await;
+  assert!(b.ban_remaining(loopback()).is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deny_and_allow_lists() {
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let a = socket().await;
+  b.deny("127.0.0.0/8".parse::<IpNet>().unwrap());
+  a.send_unreliable(b"denied", b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert!(b.try_recv().is_none());
+  assert_eq!(b.stats().filtered_packets.load(Ordering::Relaxed), 1);
+
+  // Once there's an allow list, everything else is filtered
+  b.undeny("127.0.0.0/8".parse().unwrap());
+  b.allow("10.0.0.0/8".parse().unwrap());
+  a.send_unreliable(b"not allowed", b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert!(b.try_recv().is_none());
+  b.allow("127.0.0.1/32".parse().unwrap());
+  a.send_unreliable(b"allowed", b_addr).unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"allowed");
+
+  b.ban(loopback(), Duration::from_secs(10));
+  a.send_unreliable(b"banned", b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert!(b.try_recv().is_none());
+}
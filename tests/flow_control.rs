@@ -0,0 +1,75 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::AckUdp;
+use tokio::net::UdpSocket;
+
+use common::{craft, craft_data, flags, localhost, socket, HEADER_SIZE};
+
+const COOKIE: u16 = 1 << 10;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_reader_limits_what_is_buffered() {
+  let receiver = AckUdp::builder().receive_window(4000).bind(localhost()).await.unwrap();
+  let receiver_addr = receiver.local_addr().unwrap();
+  let mut sender = socket().await;
+  let deliveries: Vec<_> = (0..20u8).map(|i| sender.send(&vec![i; 1000], receiver_addr).unwrap()).collect();
+
+  tokio::time::sleep(Duration::from_secs(1)).await;
+  assert!(receiver.buffered_bytes() <= 4000);
+
+  for _ in 0..20 {
+    let (_, datagram) = receiver.recv_timeout(Duration::from_secs(20)).await.unwrap();
+    assert_eq!(datagram.len(), 1000);
+  }
+  for delivery in deliveries {
+    delivery.await.unwrap();
+  }
+  assert_eq!(receiver.buffered_bytes(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unmatched_acks_dont_close_the_window() {
+  let mut a = AckUdp::builder().cookie_challenge(true).bind(localhost()).await.unwrap();
+  let a_addr = a.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  let raw_addr = raw.local_addr().unwrap();
+  let _delivery = a.send(b"x", raw_addr).unwrap();
+  let mut buf = [0; 2048];
+  raw.recv_from(&mut buf).await.unwrap();
+
+  // Window 0, cumulative ACK 1, for a datagram `a` never sent
+  let mut ack = 0u32.to_be_bytes().to_vec();
+  ack.extend_from_slice(&1u32.to_be_bytes());
+  raw.send_to(&craft(1, [9; 5], 0, 1, 0, &ack), a_addr).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+
+  // The next datagram isn't held back for lack of room
+  let _delivery = a.send(b"y", raw_addr).unwrap();
+  tokio::time::timeout(Duration::from_millis(400), async {
+    while raw.recv_from(&mut buf).await.unwrap().0 != HEADER_SIZE + 1 || buf[HEADER_SIZE] != b'y' {}
+  }).await.unwrap();
+
+  // Nor did the ACK prove the address: its segments are still challenged
+  raw.send_to(&craft_data([8; 5], 2, &[0; 100]), a_addr).await.unwrap();
+  tokio::time::timeout(Duration::from_millis(400), async {
+    while raw.recv_from(&mut buf).await.unwrap().0 < HEADER_SIZE || buf[9..14] != [8; 5] {}
+  }).await.unwrap();
+  assert_eq!(flags(&buf), COOKIE);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn idle_peers_are_forgotten() {
+  let a = AckUdp::builder()
+    .resend_interval(Duration::from_millis(100))
+    .max_rto(Duration::from_millis(200))
+    .incoming_expiry(Duration::from_millis(300))
+    .bind(localhost()).await.unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  a.send_unreliable(b"x", raw.local_addr().unwrap()).unwrap();
+  assert!(a.peer_congestion_window(raw.local_addr().unwrap()).is_some());
+
+  tokio::time::sleep(Duration::from_secs(1)).await;
+  assert!(a.peer_congestion_window(raw.local_addr().unwrap()).is_none());
+}
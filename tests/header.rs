@@ -0,0 +1,28 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use tokio::net::UdpSocket;
+
+use common::{localhost, socket};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn foreign_packets_are_counted_and_dropped() {
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+
+  // No magic
+  raw.send_to(&[0; 40], b_addr).await.unwrap();
+  // Another protocol version
+  let mut other_version = b"AU\x09".to_vec();
+  other_version.extend_from_slice(&[0; 30]);
+  raw.send_to(&other_version, b_addr).await.unwrap();
+  // Shorter than a header
+  raw.send_to(&[1; 3], b_addr).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+
+  assert_eq!(b.stats().incompatible_packets.load(Ordering::Relaxed), 2);
+  assert_eq!(b.stats().malformed_packets.load(Ordering::Relaxed), 1);
+  assert!(b.try_recv().is_none());
+}
@@ -0,0 +1,45 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::{AckUdp, AckUdpBuilder, AckUdpEvent, SendError};
+use tokio::net::UdpSocket;
+
+use common::localhost;
+
+fn builder() -> AckUdpBuilder {
+  AckUdp::builder()
+    .keepalive_interval(Duration::from_millis(200))
+    .peer_timeout(Duration::from_millis(1000))
+    .resend_interval(Duration::from_millis(100))
+    .max_rto(Duration::from_millis(500))
+    .incoming_expiry(Duration::from_secs(2))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pings_keep_quiet_sessions_open_until_the_peer_dies() {
+  let server = builder().accept_sessions(true).bind(localhost()).await.unwrap();
+  let mut events = server.subscribe_events();
+  let client = builder().bind(localhost()).await.unwrap();
+  let (client_session, server_session) = tokio::join!(client.connect(server.local_addr().unwrap()), server.accept());
+  let (client_session, server_session) = (client_session.unwrap(), server_session.unwrap());
+
+  // Longer than `peer_timeout` without a datagram
+  tokio::time::sleep(Duration::from_secs(3)).await;
+  assert!(!client_session.is_closed() && !server_session.is_closed());
+
+  // Gone without closing anything
+  std::mem::forget(client_session);
+  drop(client);
+  tokio::time::timeout(Duration::from_secs(3), server_session.closed()).await.unwrap();
+  assert!(matches!(events.recv().await.unwrap(), AckUdpEvent::PeerDead(_)));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deliveries_to_a_silent_peer_fail() {
+  let hole = UdpSocket::bind(localhost()).await.unwrap();
+  let mut a = builder().bind(localhost()).await.unwrap();
+
+  let result = tokio::time::timeout(Duration::from_secs(3), a.send(b"x", hole.local_addr().unwrap()).unwrap()).await.unwrap();
+  assert_eq!(result.unwrap_err(), SendError::PeerDead);
+}
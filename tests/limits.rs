@@ -0,0 +1,73 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ack_udp::{AckUdp, RejectReason, SendError};
+use tokio::net::UdpSocket;
+
+use common::{craft, craft_data, flags, localhost, socket, HEADER_SIZE, REJECTED};
+
+async fn limited_socket() -> AckUdp {
+  AckUdp::builder().max_datagram_size(10_000).max_pending_in(3).max_pending_in_per_peer(2).bind(localhost()).await.unwrap()
+}
+
+// Every rejection that arrives before the sender goes quiet, as (datagram id, reason)
+async fn rejections(socket: &UdpSocket) -> Vec<([u8; 5], u8)> {
+  let mut buf = [0; 2048];
+  let mut rejections = vec![];
+  while let Ok(Ok((length, _))) = tokio::time::timeout(Duration::from_millis(200), socket.recv_from(&mut buf)).await {
+    if flags(&buf[..length]) == REJECTED && length > HEADER_SIZE {
+      rejections.push((buf[9..14].try_into().unwrap(), buf[HEADER_SIZE]));
+    }
+  }
+
+  rejections
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oversized_datagrams_are_rejected() {
+  let b = limited_socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let mut a = socket().await;
+  let result = a.send(&[1; 20_000], b_addr).unwrap().await;
+  assert_eq!(result.unwrap_err(), SendError::Rejected(RejectReason::TooBig));
+  a.send(&[1; 9_000], b_addr).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1.len(), 9_000);
+
+  // Announcing more segments than could ever fit
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  raw.send_to(&craft_data([9; 5], u32::MAX, &[0; 100]), b_addr).await.unwrap();
+  assert_eq!(rejections(&raw).await, [([9; 5], RejectReason::TooBig as u8)]);
+
+  // Segments other than the last one can't be tiny
+  raw.send_to(&craft_data([6; 5], 500, &[0; 8]), b_addr).await.unwrap();
+  assert_eq!(rejections(&raw).await, [([6; 5], RejectReason::Malformed as u8)]);
+
+  // Opening with a small segment doesn't let the bigger ones that follow past the limit
+  raw.send_to(&craft_data([7; 5], 600, &[0; 16]), b_addr).await.unwrap();
+  let _ = rejections(&raw).await;
+  raw.send_to(&craft(0, [7; 5], 1, 600, 0, &[0; 400]), b_addr).await.unwrap();
+  assert_eq!(rejections(&raw).await, [([7; 5], RejectReason::Malformed as u8)]);
+  assert_eq!(b.buffered_bytes(), 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pending_datagrams_are_capped() {
+  let b = limited_socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  for id in 1..=3 {
+    raw.send_to(&craft_data([id; 5], 5, &[0; 100]), b_addr).await.unwrap();
+  }
+  assert_eq!(rejections(&raw).await, [([3; 5], RejectReason::TooManyPending as u8)]);
+
+  // The least recently active one makes room for another peer's
+  let other = UdpSocket::bind(localhost()).await.unwrap();
+  other.send_to(&craft_data([4; 5], 5, &[0; 100]), b_addr).await.unwrap();
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  other.send_to(&craft_data([5; 5], 5, &[0; 100]), b_addr).await.unwrap();
+  assert_eq!(rejections(&raw).await, [([1; 5], RejectReason::Evicted as u8)]);
+  assert_eq!(b.stats().evicted_datagrams.load(Ordering::Relaxed), 1);
+  // Three datagrams are held, their segments are charged their bookkeeping too
+  assert_eq!(b.buffered_bytes(), 3 * (100 + 64));
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ack_udp::{AckUdp, NoiseKeypair};
+use tokio::net::UdpSocket;
+
+use common::{craft, localhost, lossy_proxy, socket, ENCRYPTED};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peers_learn_each_others_keys() {
+  let b_keypair = NoiseKeypair::generate();
+  let a_keypair = NoiseKeypair::from_private_key(NoiseKeypair::generate().private);
+  let b = AckUdp::builder().noise_keypair(b_keypair.clone()).accept_sessions(true).bind(localhost()).await.unwrap();
+  let mut a = AckUdp::builder().noise_keypair(a_keypair.clone()).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  // Handshake messages get lost too
+  let proxy = lossy_proxy(b_addr, 0.05).await;
+  let big: Vec<u8> = (0..200_000u32).map(|v| (v * 3) as u8).collect();
+  a.send(&big, proxy).unwrap().await.unwrap();
+  let (_, key, data) = b.recv_authenticated().await.unwrap();
+  assert_eq!(data, big);
+  assert_eq!(key, Some(a_keypair.public));
+  assert_eq!(a.peer_public_key(proxy), Some(b_keypair.public));
+
+  let session = a.connect(b_addr).await.unwrap();
+  let accepted = b.accept().await.unwrap();
+  assert_eq!(session.remote_key, Some(b_keypair.public));
+  assert_eq!(accepted.remote_key, Some(a_keypair.public));
+  accepted.send(b"back").unwrap().await.unwrap();
+  assert_eq!(session.recv().await.unwrap(), b"back");
+
+  let mut plain = socket().await;
+  let plain_delivery = plain.send(b"hi", b_addr).unwrap();
+  tokio::time::sleep(Duration::from_millis(300)).await;
+  assert!(plain_delivery.try_result().is_none());
+  assert!(b.stats().unauthenticated_packets.load(Ordering::Relaxed) >= 1);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restarted_peer_handshakes_again() {
+  let b = AckUdp::builder().noise_keypair(NoiseKeypair::generate()).bind(localhost()).await.unwrap();
+  let mut a = AckUdp::builder().noise_keypair(NoiseKeypair::generate()).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  a.send(b"first", b_addr).unwrap().await.unwrap();
+  assert_eq!(b.recv().await.unwrap().1, b"first");
+
+  // Same address, new key, and none of the old session's state
+  let a_addr = a.local_addr().unwrap();
+  drop(a);
+  tokio::time::sleep(Duration::from_millis(50)).await;
+  let mut a = AckUdp::builder().noise_keypair(NoiseKeypair::generate()).bind(a_addr).await.unwrap();
+  tokio::time::timeout(Duration::from_secs(5), a.send(b"again", b_addr).unwrap()).await.unwrap().unwrap();
+  let (_, key, data) = b.recv_authenticated().await.unwrap();
+  assert_eq!(data, b"again");
+  assert_eq!(key, a.local_public_key());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unopenable_packets_get_no_answer() {
+  let b = AckUdp::builder().noise_keypair(NoiseKeypair::generate()).bind(localhost()).await.unwrap();
+  let spoofed = UdpSocket::bind(localhost()).await.unwrap();
+  // Not even a handshake, the source could be anyone's address
+  spoofed.send_to(&craft(ENCRYPTED, [1, 2, 3, 4, 5], 0, 1, 0, &[0; 40]), b.local_addr().unwrap()).await.unwrap();
+  let mut buf = [0; 2048];
+  assert!(tokio::time::timeout(Duration::from_millis(1500), spoofed.recv_from(&mut buf)).await.is_err());
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::RejectReason;
+use tokio::net::UdpSocket;
+
+use common::{craft, flags, localhost, lossy_proxy, socket, HEADER_SIZE, REJECTED};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ordered_datagrams_are_read_in_send_order() {
+  let b = socket().await;
+  let mut a = socket().await;
+  let proxy = lossy_proxy(b.local_addr().unwrap(), 0.05).await;
+  a.set_ordered_delivery(proxy, true);
+
+  // Big ones take longer to get through, later small ones wait for them
+  let deliveries: Vec<_> = (0..50u32)
+    .map(|i| {
+      let mut buf = vec![0; if i % 5 == 0 { 20_000 } else { 10 }];
+      buf[..4].copy_from_slice(&i.to_be_bytes());
+      a.send(&buf, proxy).unwrap()
+    })
+    .collect();
+  for i in 0..50u32 {
+    let (_, datagram) = b.recv_timeout(Duration::from_secs(20)).await.unwrap();
+    assert_eq!(datagram[..4], i.to_be_bytes());
+  }
+  for delivery in deliveries {
+    delivery.await.unwrap();
+  }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn late_first_datagram_doesnt_restart_the_order() {
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  raw.send_to(&craft(0, [1; 5], 0, 1, 1, b"1"), b_addr).await.unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"1");
+  // Held for 2, and ACKed already
+  raw.send_to(&craft(0, [3; 5], 0, 1, 3, b"3"), b_addr).await.unwrap();
+
+  // Another datagram numbered 1 in the same epoch is late, not a restart of the sender
+  raw.send_to(&craft(0, [9; 5], 0, 1, 1, b"late"), b_addr).await.unwrap();
+  let mut buf = [0; 2048];
+  let rejected = loop {
+    let (length, _) = tokio::time::timeout(Duration::from_secs(1), raw.recv_from(&mut buf)).await.unwrap().unwrap();
+    if flags(&buf[..length]) == REJECTED {
+      break (buf[9..14].to_vec(), buf[HEADER_SIZE]);
+    }
+  };
+  assert_eq!(rejected, ([9; 5].to_vec(), RejectReason::Late as u8));
+
+  raw.send_to(&craft(0, [2; 5], 0, 1, 2, b"2"), b_addr).await.unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"2");
+  assert_eq!(b.recv_timeout(Duration::from_secs(1)).await.unwrap().1, b"3");
+  assert!(b.try_recv().is_none());
+}
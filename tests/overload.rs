@@ -0,0 +1,27 @@
+mod common;
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use ack_udp::AckUdp;
+
+use common::{craft, localhost, UNRELIABLE};
+
+// On a single thread the processor can't run while the listener reads the flood
+#[tokio::test]
+async fn a_full_queue_drops_what_doesnt_fit() {
+  let b = AckUdp::builder().incoming_queue_capacity(4).bind(localhost()).await.unwrap();
+  let b_addr = b.local_addr().unwrap();
+  let flood = std::net::UdpSocket::bind(localhost()).unwrap();
+  for i in 0..100u8 {
+    flood.send_to(&craft(UNRELIABLE, [0, 0, 0, 0, i], 0, 1, 0, &[i]), b_addr).unwrap();
+  }
+  tokio::time::sleep(Duration::from_millis(200)).await;
+
+  let mut received = 0;
+  while b.try_recv().is_some() {
+    received += 1;
+  }
+  let overloaded = b.stats().overloaded_packets.load(Ordering::Relaxed);
+  assert!(received >= 4 && overloaded > 0);
+  assert_eq!(received + overloaded, 100);
+}
@@ -0,0 +1,52 @@
+mod common;
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::time::Instant;
+
+use common::socket;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn recv_wakes_up_once_a_datagram_arrives() {
+  let mut a = socket().await;
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+
+  let (received, _) = tokio::join!(b.recv(), async {
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    a.send(b"late", b_addr).unwrap().await.unwrap();
+  });
+  assert_eq!(received.unwrap().1, b"late");
+}
+
+#[tokio::test(start_paused = true)]
+async fn recv_timeout_gives_up() {
+  let b = socket().await;
+  let started = Instant::now();
+  assert!(b.recv_timeout(Duration::from_secs(5)).await.is_none());
+  assert_eq!(started.elapsed(), Duration::from_secs(5));
+  assert!(b.try_recv().is_none());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn every_waiter_gets_its_own_datagram() {
+  let mut a = socket().await;
+  let b = Arc::new(socket().await);
+  let b_addr = b.local_addr().unwrap();
+
+  let waiters: Vec<_> = (0..3).map(|_| {
+    let b = b.clone();
+    tokio::spawn(async move { b.recv().await.unwrap().1 })
+  }).collect();
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  for i in 0..3u8 {
+    a.send(&[i], b_addr).unwrap().await.unwrap();
+  }
+
+  let mut received = vec![];
+  for waiter in waiters {
+    received.push(tokio::time::timeout(Duration::from_secs(2), waiter).await.unwrap().unwrap());
+  }
+  received.sort();
+  assert_eq!(received, [[0], [1], [2]]);
+}
@@ -0,0 +1,15 @@
+mod common;
+
+use common::socket;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn acks_measure_the_round_trip() {
+  let mut a = socket().await;
+  let b = socket().await;
+  let b_addr = b.local_addr().unwrap();
+  assert!(a.peer_rtt(b_addr).is_none());
+
+  a.send(b"x", b_addr).unwrap().await.unwrap();
+  let estimate = a.peer_rtt(b_addr).unwrap();
+  assert!(estimate.srtt < estimate.rto && estimate.rto >= a.config().min_rto);
+}
@@ -0,0 +1,87 @@
+mod common;
+
+use std::{io::ErrorKind, time::Duration};
+
+use ack_udp::AckUdp;
+use tokio::net::UdpSocket;
+
+use common::{craft, flags, localhost, socket, UNRELIABLE};
+
+const CONNECT: u16 = 1 << 8;
+const COOKIE: u16 = 1 << 10;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sessions_carry_their_own_datagrams() {
+  let mut server = AckUdp::builder().accept_sessions(true).segment_size(200).bind(localhost()).await.unwrap();
+  let server_addr = server.local_addr().unwrap();
+  let mut client = socket().await;
+
+  let (client_session, server_session) = tokio::join!(client.connect(server_addr), server.accept());
+  let (client_session, server_session) = (client_session.unwrap(), server_session.unwrap());
+  // The smaller segment size of both sides
+  assert_eq!((client_session.segment_size, server_session.segment_size), (200, 200));
+  assert_eq!(client_session.id, server_session.id);
+
+  client_session.send(&vec![1; 5000]).unwrap().await.unwrap();
+  assert_eq!(server_session.recv().await.unwrap().len(), 5000);
+  server_session.send(b"back").unwrap().await.unwrap();
+  // Not visible to the socket's own `recv`
+  assert!(client.try_recv().is_none());
+  assert_eq!(client_session.recv().await.unwrap(), b"back");
+
+  client_session.close().await;
+  tokio::time::timeout(Duration::from_secs(2), server_session.closed()).await.unwrap();
+  assert!(server_session.recv().await.is_none());
+  assert!(server_session.send(b"late").is_err());
+  // Both sockets forgot the session, their own `recv` reads what the other sends now
+  client.send(b"after", server_addr).unwrap().await.unwrap();
+  assert_eq!(server.recv().await.unwrap(), (client.local_addr().unwrap(), b"after".to_vec()));
+  server.send(b"after", client.local_addr().unwrap()).unwrap().await.unwrap();
+  assert_eq!(client.recv().await.unwrap().1, b"after");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn connect_fails_when_refused_or_unanswered() {
+  let client = AckUdp::builder().handshake_timeout(Duration::from_millis(700)).bind(localhost()).await.unwrap();
+  let refusing = socket().await;
+  let error = client.connect(refusing.local_addr().unwrap()).await.err().unwrap();
+  assert_eq!(error.kind(), ErrorKind::ConnectionRefused);
+
+  let silent = UdpSocket::bind(localhost()).await.unwrap();
+  let error = client.connect(silent.local_addr().unwrap()).await.err().unwrap();
+  assert_eq!(error.kind(), ErrorKind::TimedOut);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dropping_a_session_closes_it() {
+  let server = AckUdp::builder().accept_sessions(true).bind(localhost()).await.unwrap();
+  let client = socket().await;
+  let (client_session, server_session) = tokio::join!(client.connect(server.local_addr().unwrap()), server.accept());
+  let server_session = server_session.unwrap();
+
+  drop(client_session);
+  tokio::time::timeout(Duration::from_secs(2), server_session.closed()).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreliable_session_datagrams_answer_cookies() {
+  let server = AckUdp::builder().accept_sessions(true).bind(localhost()).await.unwrap();
+  let server_addr = server.local_addr().unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  raw.send_to(&craft(CONNECT, [5; 5], 0, 1, 0, &400u16.to_be_bytes()), server_addr).await.unwrap();
+  let session = tokio::time::timeout(Duration::from_secs(2), server.accept()).await.unwrap().unwrap();
+
+  // Challenged like any unreliable datagram of the socket itself
+  session.send_unreliable(b"x").unwrap();
+  let mut buf = [0; 2048];
+  let mut id = [0; 5];
+  tokio::time::timeout(Duration::from_secs(1), async {
+    while raw.recv_from(&mut buf).await.unwrap().0 == 0 || flags(&buf) != UNRELIABLE {}
+  }).await.unwrap();
+  id.copy_from_slice(&buf[9..14]);
+  raw.send_to(&craft(COOKIE, id, 0, 1, 0, b"cookie"), server_addr).await.unwrap();
+  tokio::time::timeout(Duration::from_secs(1), async {
+    while raw.recv_from(&mut buf).await.unwrap().0 == 0 || flags(&buf) != COOKIE | 1 {}
+  }).await.unwrap();
+  assert_eq!(buf[9..14], id);
+}
@@ -0,0 +1,35 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::SendError;
+use common::socket;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn shutdown_drains_then_stops() {
+  let mut a = socket().await;
+  let b = socket().await;
+  let delivery = a.send(b"last", b.local_addr().unwrap()).unwrap();
+
+  a.shutdown(Some(Duration::from_secs(1))).await;
+  assert!(delivery.await.is_ok());
+  assert_eq!(b.recv().await.unwrap().1, b"last");
+  b.shutdown(None).await;
+  assert!(b.recv().await.is_none());
+  assert!(a.send(b"late", b.local_addr().unwrap()).is_err());
+}
+
+#[tokio::test]
+async fn shutdown_on_current_thread_runtime() {
+  socket().await.shutdown(None).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn drop_fails_pending_deliveries() {
+  let hole = tokio::net::UdpSocket::bind(common::localhost()).await.unwrap();
+  let mut a = socket().await;
+  let delivery = a.send(b"never", hole.local_addr().unwrap()).unwrap();
+
+  drop(a);
+  assert_eq!(tokio::time::timeout(Duration::from_secs(1), delivery).await.unwrap().unwrap_err(), SendError::Shutdown);
+}
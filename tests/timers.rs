@@ -0,0 +1,51 @@
+mod common;
+
+use std::time::Duration;
+
+use ack_udp::{AckUdp, SendError};
+use tokio::{net::UdpSocket, time::Instant};
+
+use common::{craft_data, flags, localhost};
+
+// The clock only moves when every task waits on a timer, so the timeouts are exact up to a couple of `timer_granularity` ticks
+#[tokio::test(start_paused = true)]
+async fn retransmits_back_off_until_the_datagram_is_dropped() {
+  let mut a = AckUdp::builder()
+    .resend_interval(Duration::from_millis(500))
+    .max_rto(Duration::from_secs(2))
+    .max_resend_attempts(3)
+    .bind(localhost()).await.unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  let delivery = a.send(b"x", raw.local_addr().unwrap()).unwrap();
+
+  let mut buf = [0; 2048];
+  let mut sent_at = vec![];
+  while sent_at.len() < 4 {
+    raw.recv_from(&mut buf).await.unwrap();
+    if flags(&buf) == 0 {
+      sent_at.push(Instant::now());
+    }
+  }
+  let gaps: Vec<Duration> = sent_at.windows(2).map(|pair| pair[1] - pair[0]).collect();
+  for (gap, rto) in gaps.into_iter().zip([500, 1000, 2000]) {
+    assert!(gap.abs_diff(Duration::from_millis(rto)) <= Duration::from_millis(20), "{gap:?} instead of {rto}ms");
+  }
+
+  assert_eq!(delivery.await.unwrap_err(), SendError::Dropped { attempts: 3 });
+  let waited = sent_at[3].elapsed();
+  assert!(waited.abs_diff(Duration::from_secs(2)) <= Duration::from_millis(20), "{waited:?} instead of 2s");
+}
+
+#[tokio::test(start_paused = true)]
+async fn incomplete_datagrams_expire() {
+  let b = AckUdp::builder().incoming_expiry(Duration::from_secs(10)).bind(localhost()).await.unwrap();
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  raw.send_to(&craft_data([1; 5], 2, &[0; 100]), b.local_addr().unwrap()).await.unwrap();
+
+  tokio::time::sleep(Duration::from_millis(100)).await;
+  assert_eq!(b.buffered_bytes(), 100 + 64);
+  tokio::time::sleep(Duration::from_millis(9800)).await;
+  assert_eq!(b.buffered_bytes(), 100 + 64);
+  tokio::time::sleep(Duration::from_millis(200)).await;
+  assert_eq!(b.buffered_bytes(), 0);
+}
@@ -0,0 +1,32 @@
+mod common;
+
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use common::{localhost, socket};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unreliable_datagrams_leave_nothing_behind() {
+  let b = socket().await;
+  let a = socket().await;
+  let b_addr = b.local_addr().unwrap();
+
+  a.send_unreliable(b"small", b_addr).unwrap();
+  a.send_unreliable(&vec![3; 3000], b_addr).unwrap();
+  assert_eq!(b.recv_timeout(Duration::from_secs(2)).await.unwrap().1, b"small");
+  assert_eq!(b.recv_timeout(Duration::from_secs(2)).await.unwrap().1, vec![3; 3000]);
+
+  // No ACK to measure the round trip with
+  assert!(a.peer_rtt(b_addr).is_none());
+
+  // Nothing is kept around to resend, a peer that never answers gets each segment once
+  let raw = UdpSocket::bind(localhost()).await.unwrap();
+  a.send_unreliable(&vec![3; 3000], raw.local_addr().unwrap()).unwrap();
+  let mut buf = [0; 2048];
+  let mut received = 0;
+  while tokio::time::timeout(Duration::from_millis(1500), raw.recv_from(&mut buf)).await.is_ok() {
+    received += 1;
+  }
+  assert_eq!(received, 3000usize.div_ceil(a.config().segment_size));
+}